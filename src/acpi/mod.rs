@@ -5,29 +5,53 @@ use acpi::sdt::Signature;
 use acpi::handler::AcpiHandler;
 use core::mem;
 use crate::println;
-use acpi::AcpiTables;
+use acpi::{AcpiTables, PhysicalMapping};
+use crate::memory::{paging::ACPI_WINDOW_START, window::{self, VirtualWindow}, FRAME_SIZE};
+use core::ptr::NonNull;
+use spin::Mutex;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
 
 
 
+static ACPI_WINDOW: Mutex<VirtualWindow<64>> = Mutex::new(VirtualWindow::new(ACPI_WINDOW_START));
+
+
+/// Maps ACPI tables read only into their own window and unmaps them when the mapping is dropped.
 #[derive(Clone, Debug)]
-pub struct DummyHandler {}
+pub struct KernelHandler;
 
-impl AcpiHandler for DummyHandler {
+impl AcpiHandler for KernelHandler {
     unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> acpi::PhysicalMapping<Self, T> {
+        let virtual_address = window::map(
+            &ACPI_WINDOW,
+            PhysAddr::new(physical_address as u64),
+            size,
+            PageTableFlags::NO_EXECUTE,
+        ).expect("ACPI window exhausted");
+
+        let offset = physical_address % FRAME_SIZE as usize;
+        let mapped_length = (offset + size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize * FRAME_SIZE as usize;
+
         acpi::PhysicalMapping::new(
-            physical_address, 
-            core::ptr::NonNull::new(physical_address as *mut T).unwrap(), 
-            size, 
-            size, 
+            physical_address,
+            NonNull::new(virtual_address.as_mut_ptr()).unwrap(),
+            size,
+            mapped_length,
             self.clone(),
         )
     }
-    
-    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {}
+
+    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
+        window::unmap(
+            &ACPI_WINDOW,
+            VirtAddr::from_ptr(region.virtual_start().as_ptr()),
+            region.region_length(),
+        );
+    }
 }
 
 
-pub fn read(mbi: &multiboot2::BootInformation) -> AcpiTables<DummyHandler> {
+pub fn read(mbi: &multiboot2::BootInformation) -> AcpiTables<KernelHandler> {
     let rsdp = mbi.rsdp_v1_tag().unwrap();
     let rsdp_ptr = match mbi.rsdp_v2_tag() {
         // 8 is the size of the multiboot2 header
@@ -37,25 +61,70 @@ pub fn read(mbi: &multiboot2::BootInformation) -> AcpiTables<DummyHandler> {
 
     unsafe {
         acpi::AcpiTables::from_rsdp(
-            DummyHandler{}, 
+            KernelHandler, 
             rsdp_ptr,
         ).unwrap()
     }
 }
 
 
-pub fn read_madt<'a>(acpi_tables: &AcpiTables<DummyHandler>) -> &'a Madt {
-    let madt = unsafe {
-        acpi_tables.find_table::<Madt>().unwrap().virtual_start().as_ref()
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Calls `f` with the physical address and length of the (X)SDT, every table it lists and the DSDT.
+///
+/// Reads the tables through the identity map, it is used by the frame allocator before there is a heap.
+pub fn for_each_table_region(mbi: &multiboot2::BootInformation, mut f: impl FnMut(usize, usize)) {
+    let (sdt_addr, entry_size) = match mbi.rsdp_v2_tag() {
+        Some(rsdp) if rsdp.xsdt_address() != 0 => (rsdp.xsdt_address(), 8),
+        _ => match mbi.rsdp_v1_tag() {
+            Some(rsdp) => (rsdp.rsdt_address(), 4),
+            None => return,
+        },
     };
+
+    unsafe {
+        let table_len = |addr: usize| ((addr + 4) as *const u32).read_unaligned() as usize;
+
+        let sdt_len = table_len(sdt_addr);
+        f(sdt_addr, sdt_len);
+
+        // a corrupt length below the header size lists no tables
+        for i in 0..sdt_len.saturating_sub(SDT_HEADER_SIZE) / entry_size {
+            let entry_addr = sdt_addr + SDT_HEADER_SIZE + i * entry_size;
+            let table_addr = match entry_size {
+                8 => (entry_addr as *const u64).read_unaligned() as usize,
+                _ => (entry_addr as *const u32).read_unaligned() as usize,
+            };
+            let len = table_len(table_addr);
+            f(table_addr, len);
+
+            // the DSDT is only referenced from the FADT
+            if (table_addr as *const [u8; 4]).read() == *b"FACP" {
+                let x_dsdt = match len >= 148 {
+                    true => ((table_addr + 140) as *const u64).read_unaligned() as usize,
+                    false => 0,
+                };
+                let dsdt = match x_dsdt {
+                    0 => ((table_addr + 40) as *const u32).read_unaligned() as usize,
+                    addr => addr,
+                };
+                if dsdt != 0 {
+                    f(dsdt, table_len(dsdt));
+                }
+            }
+        }
+    }
+}
+
+
+pub fn read_madt(acpi_tables: &AcpiTables<KernelHandler>) -> PhysicalMapping<KernelHandler, Madt> {
+    let madt = acpi_tables.find_table::<Madt>().unwrap();
     madt.header.validate(acpi::sdt::Signature::MADT).unwrap();
     madt
 }
 
-pub fn read_fadt<'a>(acpi_tables: &AcpiTables<DummyHandler>) -> &'a Fadt {
-    let fadt = unsafe {
-        acpi_tables.find_table::<Fadt>().unwrap().virtual_start().as_ref()
-    };
+pub fn read_fadt(acpi_tables: &AcpiTables<KernelHandler>) -> PhysicalMapping<KernelHandler, Fadt> {
+    let fadt = acpi_tables.find_table::<Fadt>().unwrap();
     fadt.validate().unwrap();
     fadt
 }
@@ -0,0 +1,111 @@
+; entry point of the application processors, copied to AP_TRAMPOLINE_BASE by cpu::smp
+; the startup ipi starts it in real mode at AP_TRAMPOLINE_BASE:0 with paging and interrupts off
+
+global ap_trampoline_start
+global ap_trampoline_data
+global ap_trampoline_end
+
+AP_TRAMPOLINE_BASE equ 0x8000
+
+; address of a label once the trampoline was copied
+%define rel_addr(label) (AP_TRAMPOLINE_BASE + (label - ap_trampoline_start))
+
+
+section .text
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+
+    ; cs is AP_TRAMPOLINE_BASE >> 4
+    mov ax, cs
+    mov ds, ax
+
+    lgdt [ap_gdt.pointer - ap_trampoline_start]
+
+    ; enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword ap_gdt.code32:rel_addr(ap_protected_mode)
+
+
+bits 32
+ap_protected_mode:
+    mov ax, ap_gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; enable PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    ; the kernel page tables, they have to be below 4 GiB
+    mov eax, [rel_addr(ap_trampoline_data.cr3)]
+    mov cr3, eax
+
+    ; set the long mode and no execute bits
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    ; enable paging and write protection
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    jmp ap_gdt.code64:rel_addr(ap_long_mode)
+
+
+bits 64
+ap_long_mode:
+    mov ax, ap_gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [rel_addr(ap_trampoline_data.stack)]
+    mov rdi, [rel_addr(ap_trampoline_data.cpu)]
+    mov rax, [rel_addr(ap_trampoline_data.entry)]
+    call rax
+
+    ; the entry never returns
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+
+align 8
+; temporary gdt until the ap loads its own
+; the accessed bits are preset, the page is read-only once paging is on
+ap_gdt:
+    dq 0
+.code32: equ $ - ap_gdt
+    dq 0x00CF9B000000FFFF
+.data: equ $ - ap_gdt
+    dq 0x00CF93000000FFFF
+.code64: equ $ - ap_gdt
+    dq (1<<40) | (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53)
+
+.pointer:
+    dw .pointer - ap_gdt - 1
+    dd rel_addr(ap_gdt)
+
+
+; filled in by cpu::smp before every startup ipi, has to match `TrampolineData`
+align 8
+ap_trampoline_data:
+.cr3: dq 0
+.stack: dq 0
+.entry: dq 0
+.cpu: dq 0
+
+ap_trampoline_end:
@@ -13,6 +13,9 @@ _start:
     cmp eax, 0x36d76289
     jne multiboot_not_compliant
 
+    ; the multiboot2 spec doesn't guarantee a usable stack
+    mov esp, stack_top
+
     ; save the initial state and pass it to kmain
     push ebx
     mov edi, ebx
@@ -59,7 +62,11 @@ print:
     push dx
     push ax
 
-    mov dx, 0x3F8   ; COM1
+    mov dx, [0x400] ; COM1 from the BIOS data area
+    test dx, dx
+    jnz .port_found
+    mov dx, 0x3F8   ; no entry, assume the standard COM1 port
+    .port_found:
     cld             ; clear the direction flag (increment)
 
     print_loop:
@@ -97,6 +104,13 @@ unsupported_multiboot2_version:
     call print
     hlt
 
+section .bss
+align 4096
+stack_bottom:
+    resb 64 * 1024
+stack_top:
+
+
 section .rodata
 ; dummy gdt for transitioning to long mode
 gdt64:
@@ -1,42 +1,360 @@
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
-static mut SERIAL_WRITER: Mutex<SerialWriter> = Mutex::new(SerialWriter::new(0x3F8));
-struct SerialWriter {
-    port: Port<u8>,
+pub const COM1: u16 = 0x3F8;
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+// the divisor latch counts down from this
+const UART_CLOCK: u32 = 115200;
+
+// register offsets, the first two are the divisor latch while LCR_DLAB is set
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+// enable and clear both FIFOs, interrupt at 14 bytes
+const FCR_ENABLE_CLEAR_14: u8 = 0xC7;
+const MCR_DTR_RTS_OUT2: u8 = 0x0B;
+const MCR_LOOPBACK: u8 = 0x1E;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+
+const TEST_BYTE: u8 = 0xAE;
+const LOOPBACK_SPINS: usize = 1000;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The scratch register didn't keep its value, there is no UART at the port.
+    NotPresent,
+    /// A byte sent in loopback mode didn't come back.
+    LoopbackFailed,
+    InvalidBaudRate,
 }
 
-impl SerialWriter {
-    const fn new(port_addr: u16) -> Self {
+
+/// A 16550 compatible UART.
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    pub const fn new(base: u16) -> Self {
+        Uart { base }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Checks that the UART exists and works, then sets it to `baud_rate` 8N1 with FIFOs.
+    ///
+    /// Interrupts stay disabled, see `enable_rx_interrupt`.
+    pub fn init(&self, baud_rate: u32) -> Result<(), UartError> {
+        if !is_valid_baud_rate(baud_rate) {
+            return Err(UartError::InvalidBaudRate);
+        }
+
+        self.write(SCRATCH, TEST_BYTE);
+        if self.read(SCRATCH) != TEST_BYTE {
+            return Err(UartError::NotPresent);
+        }
+
+        let divisor = (UART_CLOCK / baud_rate) as u16;
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, LCR_DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, LCR_8N1);
+        self.write(FIFO_CONTROL, FCR_ENABLE_CLEAR_14);
+
+        self.write(MODEM_CONTROL, MCR_LOOPBACK);
+        self.write(DATA, TEST_BYTE);
+        let echoed = (0..LOOPBACK_SPINS).find_map(|_| self.read_byte());
+        // OUT2 gates the interrupt line
+        self.write(MODEM_CONTROL, MCR_DTR_RTS_OUT2);
+        if echoed != Some(TEST_BYTE) {
+            return Err(UartError::LoopbackFailed);
+        }
+        Ok(())
+    }
+
+    pub fn enable_rx_interrupt(&self) {
+        self.write(INTERRUPT_ENABLE, IER_RX_AVAILABLE);
+    }
+
+    /// Waits for the transmit holding register to be empty and sends `byte`.
+    pub fn write_byte(&self, byte: u8) {
+        while self.read(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// A received byte, if there is one.
+    pub fn read_byte(&self) -> Option<u8> {
+        match self.read(LINE_STATUS) & LSR_DATA_READY {
+            0 => None,
+            _ => Some(self.read(DATA)),
+        }
+    }
+}
+
+
+/// The divisor latch can only divide the UART clock by a whole number that fits 16 bits.
+fn is_valid_baud_rate(baud_rate: u32) -> bool {
+    baud_rate != 0 && UART_CLOCK % baud_rate == 0 && UART_CLOCK / baud_rate <= u16::MAX as u32
+}
+
+
+/// Standard port of each ttyS index, used when the BIOS data area has no entry.
+pub const LEGACY_PORTS: [u16; PORT_COUNT] = [COM1, 0x2F8, 0x3E8, 0x2E8];
+pub const PORT_COUNT: usize = 4;
+// physical address of the BIOS data area's COM port table
+const BDA_COM_PORTS: u64 = 0x400;
+const NO_PORT: u8 = u8::MAX;
+
+static mut CONSOLE: Mutex<Console> = Mutex::new(Console::new());
+// index and base of the port the debug shell reads from, readable from interrupt handlers
+// without the console lock, the base is stored first
+static SHELL_PORT: AtomicU8 = AtomicU8::new(NO_PORT);
+static SHELL_BASE: AtomicU16 = AtomicU16::new(0);
+// set for good once the kernel panics, the console lock is bypassed from then on
+static PANIC_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Every UART found and where `print` goes.
+struct Console {
+    // indexed by ttyS number
+    ports: [Option<Uart>; PORT_COUNT],
+    // a bit per ttyS number that gets the log output
+    log_sinks: u8,
+}
+
+impl Console {
+    const fn new() -> Self {
         Self {
-            port: Port::new(port_addr)
+            ports: [None; PORT_COUNT],
+            log_sinks: 0,
+        }
+    }
+
+    fn write_port(&self, index: usize, s: &str) {
+        let uart = match self.ports[index] {
+            Some(uart) => uart,
+            None => return,
+        };
+        for byte in s.bytes() {
+            match byte {
+                // '\n' doesn't insert the carry return by itself so it has to be done manually
+                b'\n' => {
+                    uart.write_byte(0x0A);
+                    uart.write_byte(0x0D);
+                }
+
+                _ => {
+                    uart.write_byte(byte);
+                }
+            }
         }
     }
 }
 
-impl fmt::Write for SerialWriter {
+impl fmt::Write for Console {
     #[no_mangle]
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        unsafe {
-            for byte in s.bytes() {
-                match byte {
-                    // '\n' doesn't insert the carry return by itself so it has to be done manually
-                    b'\n' => {
-                        self.port.write(0x0A);
-                        self.port.write(0x0D);
-                    }
-                        
-                    _ => {
-                        self.port.write(byte);
-                    }
-                } 
+        for index in 0..PORT_COUNT {
+            if self.log_sinks & 1 << index != 0 {
+                self.write_port(index, s);
             }
         }
         Ok(())
     }
 }
 
+/// Writes to a single port, used by the debug shell.
+struct PortWriter<'a> {
+    console: &'a Console,
+    index: usize,
+}
+
+impl fmt::Write for PortWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.console.write_port(self.index, s);
+        Ok(())
+    }
+}
+
+fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> R {
+    if PANIC_MODE.load(Ordering::Acquire) {
+        // the lock may be held by a halted cpu or by the code the panic interrupted
+        return f(unsafe { CONSOLE.get_mut() });
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe { f(&mut CONSOLE.lock()) })
+}
+
+/// Makes every write skip the console lock so panic output can't deadlock.
+///
+/// Output of a write that held the lock can get mixed into the panic message.
+pub fn enter_panic_mode() {
+    PANIC_MODE.store(true, Ordering::Release);
+}
+
+
+/// Sets up COM1 as the early console, has to run before anything is printed.
+///
+/// `init_ports` moves ttyS0 to the port the BIOS data area lists for it.
+pub fn init() -> Result<(), UartError> {
+    with_console(|console| {
+        let uart = Uart::new(COM1);
+        uart.init(DEFAULT_BAUD_RATE)?;
+        console.ports[0] = Some(uart);
+        console.log_sinks = 1;
+        Ok(())
+    })
+}
+
+/// Probes all four COM ports and applies the `console=` and `shell=` options of the
+/// kernel command line.
+///
+/// `console=ttyS<n>[,<baud>]` may be given more than once, every port listed gets the log.
+/// `shell=ttyS<n>[,<baud>]` picks the port the debug shell reads from, by default the
+/// first log port. Needs the physical memory mapping for the BIOS data area.
+pub fn init_ports(cmdline: &str) {
+    let bda = crate::memory::phys_to_virt(x86_64::PhysAddr::new(BDA_COM_PORTS));
+    let bda_ports = unsafe { (bda.as_ptr::<[u16; PORT_COUNT]>()).read_unaligned() };
+
+    let found = with_console(|console| {
+        for index in 0..PORT_COUNT {
+            let base = match bda_ports[index] {
+                0 => LEGACY_PORTS[index],
+                base => base,
+            };
+            // ttyS0 was set up at COM1 before the BIOS data area could be read
+            if console.ports[index].map(|uart| uart.base()) == Some(base) {
+                continue;
+            }
+            let uart = Uart::new(base);
+            if uart.init(DEFAULT_BAUD_RATE).is_ok() {
+                console.ports[index] = Some(uart);
+            }
+        }
+        console.ports
+    });
+    for (index, uart) in found.iter().enumerate() {
+        if let Some(uart) = uart {
+            log::info!("ttyS{} at {:#x}", index, uart.base());
+        }
+    }
+
+    let mut log_sinks = 0u8;
+    let mut shell = None;
+    for option in cmdline.split_whitespace() {
+        let (key, value) = match option.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if key != "console" && key != "shell" {
+            continue;
+        }
+        let (index, baud_rate) = match parse_port(value) {
+            Some(port) => port,
+            None => {
+                log::warn!("ignoring {}={}, expected ttyS<0-3>[,<baud>]", key, value);
+                continue;
+            }
+        };
+
+        let configured = with_console(|console| {
+            let uart = console.ports[index].ok_or(UartError::NotPresent)?;
+            uart.init(baud_rate)
+        });
+        if let Err(error) = configured {
+            log::warn!("can't use ttyS{} for {}: {:?}", index, key, error);
+            continue;
+        }
+        match key {
+            "console" => log_sinks |= 1 << index,
+            _ => shell = Some(index),
+        }
+    }
+
+    with_console(|console| {
+        if log_sinks != 0 {
+            console.log_sinks = log_sinks;
+        }
+        let default_shell = (console.log_sinks != 0).then(|| console.log_sinks.trailing_zeros() as usize);
+        let shell = shell.or(default_shell).and_then(|index| Some((index, console.ports[index]?)));
+        if let Some((index, uart)) = shell {
+            SHELL_BASE.store(uart.base(), Ordering::Relaxed);
+            SHELL_PORT.store(index as u8, Ordering::Release);
+        }
+    });
+}
+
+/// Parses `ttyS<n>[,<baud>]`.
+pub(crate) fn parse_port(value: &str) -> Option<(usize, u32)> {
+    let (device, baud_rate) = match value.split_once(',') {
+        Some((device, baud_rate)) => (device, baud_rate.parse().ok()?),
+        None => (value, DEFAULT_BAUD_RATE),
+    };
+    let index: usize = device.strip_prefix("ttyS")?.parse().ok()?;
+    (index < PORT_COUNT && is_valid_baud_rate(baud_rate)).then_some((index, baud_rate))
+}
+
+/// Adds a port to the ports `print` writes to.
+pub fn add_log_sink(index: usize) -> Result<(), UartError> {
+    with_console(|console| {
+        console.ports.get(index).copied().flatten().ok_or(UartError::NotPresent)?;
+        console.log_sinks |= 1 << index;
+        Ok(())
+    })
+}
+
+pub fn remove_log_sink(index: usize) {
+    with_console(|console| console.log_sinks &= !(1 << index));
+}
+
+/// The debug shell's port as ttyS index and UART, doesn't take the console lock.
+pub fn shell_port() -> Option<(usize, Uart)> {
+    let index = match SHELL_PORT.load(Ordering::Acquire) {
+        NO_PORT => return None,
+        index => index as usize,
+    };
+    Some((index, Uart::new(SHELL_BASE.load(Ordering::Relaxed))))
+}
+
+/// ISA IRQ of a legacy port, COM1 and COM3 share IRQ 4, COM2 and COM4 IRQ 3.
+pub fn isa_irq(index: usize) -> u8 {
+    match index % 2 {
+        0 => 4,
+        _ => 3,
+    }
+}
+
+/// Writes to the port with ttyS `index` only, whether it carries the log or not.
+pub fn write_port(index: usize, args: fmt::Arguments) {
+    with_console(|console| {
+        let _ = PortWriter { console, index }.write_fmt(args);
+    });
+}
+
 
 #[macro_export]
 macro_rules! print {
@@ -50,10 +368,5 @@ macro_rules! println {
 }
 
 pub fn _print(args: fmt::Arguments) {
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| unsafe {
-        SERIAL_WRITER.lock().write_fmt(args).unwrap();
-    });
+    with_console(|console| console.write_fmt(args).unwrap());
 }
-
@@ -0,0 +1,44 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+
+pub mod percpu;
+pub mod smp;
+
+
+static CPUS: Once<Vec<Cpu>> = Once::new();
+
+
+/// A processor listed in the MADT, index 0 is always the BSP.
+#[derive(Debug)]
+pub struct Cpu {
+    pub index: usize,
+    pub apic_id: u32,
+    online: AtomicBool,
+}
+
+impl Cpu {
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+}
+
+
+/// Every enabled processor, empty before `smp::init`.
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Index of the running CPU into `cpus`.
+pub fn current_index() -> usize {
+    *crate::percpu!(index)
+}
+
+/// Number of processors that finished their startup, including the BSP.
+pub fn online_cpus() -> usize {
+    cpus().iter().filter(|cpu| cpu.is_online()).count()
+}
@@ -0,0 +1,139 @@
+use crate::task::{Priority, TaskId};
+use crate::CPUID;
+use alloc::{boxed::Box, vec};
+use core::arch::asm;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use x86::msr::{wrmsr, IA32_GS_BASE, IA32_KERNEL_GSBASE};
+use x86_64::VirtAddr;
+
+
+pub const MAX_CPUS: usize = 64;
+const SCRATCH_STACK_SIZE: usize = 16 * 1024;
+const CALL_QUEUE_CAPACITY: usize = 32;
+const NO_TASK: u64 = u64::MAX;
+
+static BLOCKS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+
+/// Data owned by one CPU, the running CPU finds its own block through the GS base.
+#[repr(C)]
+pub struct PerCpu {
+    // has to stay the first field, `current` reads it from gs:0
+    this: *const PerCpu,
+    pub index: usize,
+    pub apic_id: u32,
+    /// Top of a small stack for code that can't trust the one it is running on.
+    pub scratch_stack: VirtAddr,
+    /// Tasks ready to run on this CPU by priority, other CPUs may steal from them.
+    pub run_queues: [SegQueue<TaskId>; Priority::COUNT],
+    /// Functions other CPUs asked this one to run, see `ipi::call_on`.
+    pub(crate) call_queue: ArrayQueue<Box<dyn FnOnce() + Send>>,
+    /// Threads of this CPU, see `thread`.
+    pub(crate) threads: crate::thread::CpuThreads,
+    /// Budget left to the task being polled, see `task::coop`.
+    pub(crate) poll_budget: AtomicU32,
+    /// Records logged on this CPU that aren't written out yet, see `klog`.
+    pub(crate) log_ring: crate::klog::LogRing,
+    current_task: AtomicU64,
+    interrupt_depth: AtomicU32,
+}
+
+// `this` only ever points at the block itself
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub fn current_task(&self) -> Option<TaskId> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(TaskId(id)),
+        }
+    }
+
+    pub fn set_current_task(&self, task: Option<TaskId>) {
+        let id = task.map_or(NO_TASK, |task| task.0);
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    /// How many interrupt handlers are running on this CPU, 0 in task context.
+    pub fn interrupt_depth(&self) -> u32 {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn enter_interrupt(&self) {
+        self.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn leave_interrupt(&self) {
+        self.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Allocates the block of the calling CPU and points the GS base at it.
+///
+/// Has to run before interrupts are enabled on the CPU. The kernel GS base is left at 0,
+/// it is swapped in with `swapgs` once there is a user mode.
+pub fn init(index: usize) {
+    assert!(index < MAX_CPUS, "cpu index {} is above MAX_CPUS", index);
+    let apic_id = initial_apic_id();
+
+    let stack: &'static mut [u8] = Box::leak(vec![0u8; SCRATCH_STACK_SIZE].into_boxed_slice());
+    let block = Box::leak(Box::new(PerCpu {
+        this: null_mut(),
+        index,
+        apic_id,
+        scratch_stack: VirtAddr::from_ptr(stack.as_ptr()) + SCRATCH_STACK_SIZE,
+        run_queues: [const { SegQueue::new() }; Priority::COUNT],
+        threads: crate::thread::CpuThreads::new(),
+        poll_budget: AtomicU32::new(0),
+        log_ring: crate::klog::LogRing::new(),
+        call_queue: ArrayQueue::new(CALL_QUEUE_CAPACITY),
+        current_task: AtomicU64::new(NO_TASK),
+        interrupt_depth: AtomicU32::new(0),
+    }));
+    block.this = block;
+
+    BLOCKS[index].store(block, Ordering::Release);
+    unsafe {
+        wrmsr(IA32_GS_BASE, block as *const PerCpu as u64);
+        wrmsr(IA32_KERNEL_GSBASE, 0);
+    }
+}
+
+/// The local APIC may not be set up yet, so the ID comes from CPUID.
+fn initial_apic_id() -> u32 {
+    CPUID.get_extended_topology_info()
+        .and_then(|mut levels| levels.next())
+        .map(|level| level.x2apic_id())
+        .or_else(|| CPUID.get_feature_info().map(|info| info.initial_local_apic_id() as u32))
+        .unwrap_or(0)
+}
+
+/// Block of the running CPU, only valid after `init` ran on it.
+pub fn current() -> &'static PerCpu {
+    let block: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) block, options(nostack, readonly, preserves_flags));
+        &*block
+    }
+}
+
+/// Block of another CPU, `None` until that CPU is started.
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    let block = BLOCKS.get(index)?.load(Ordering::Acquire);
+    unsafe { block.as_ref() }
+}
+
+
+/// Reference to a field of the running CPU's `PerCpu` block.
+///
+/// The CPU may change after the call unless interrupts are disabled.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::cpu::percpu::current().$field
+    };
+}
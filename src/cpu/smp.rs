@@ -0,0 +1,181 @@
+use super::{cpus, online_cpus, Cpu, CPUS};
+use crate::acpi::acpi::madt::{Madt, MadtEntry};
+use crate::interrupts::{self, apic::LOCAL_APIC};
+use crate::memory::{paging, phys_to_virt, stack::Stack};
+use crate::task::executor::Executor;
+use crate::{gdt, thread, time};
+use alloc::vec;
+use core::sync::atomic::AtomicBool;
+use core::time::Duration;
+use x86::apic::ApicControl;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+
+/// Physical address the trampoline is copied to, the startup IPI vector is its page number.
+const AP_TRAMPOLINE_BASE: u64 = 0x8000;
+const AP_STACK_SIZE: usize = 64 * 1024;
+const AP_STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Parameters the trampoline in `ap_trampoline.asm` reads, has to match its layout.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+
+/// Collects the enabled processors from the MADT and starts every application processor.
+pub fn init(madt: &Madt) {
+    let (bsp_apic_id, x2apic) = unsafe { (LOCAL_APIC.apic_id(), LOCAL_APIC.is_x2apic()) };
+
+    let mut found = vec![Cpu {
+        index: 0,
+        apic_id: bsp_apic_id,
+        online: AtomicBool::new(true),
+    }];
+    for entry in madt.entries() {
+        let (apic_id, flags) = match entry {
+            MadtEntry::LocalApic(entry) => (entry.apic_id as u32, entry.flags),
+            MadtEntry::LocalX2Apic(entry) => (entry.x2apic_id, entry.flags),
+            _ => continue,
+        };
+
+        // bit 0 is enabled, processors that are only online capable are left alone
+        if flags & 1 == 0 || found.iter().any(|cpu| cpu.apic_id == apic_id) {
+            continue;
+        }
+        if found.len() == super::percpu::MAX_CPUS {
+            log::warn!("more than {} cpus, skipping apic id {}", super::percpu::MAX_CPUS, apic_id);
+            continue;
+        }
+        if apic_id > 0xFF && !x2apic {
+            log::warn!("cpu with apic id {} needs x2apic, skipping it", apic_id);
+            continue;
+        }
+
+        found.push(Cpu {
+            index: found.len(),
+            apic_id,
+            online: AtomicBool::new(false),
+        });
+    }
+
+    let all = CPUS.call_once(|| found);
+    if all.len() > 1 {
+        let data = unsafe { install_trampoline() };
+        for cpu in &all[1..] {
+            start_ap(cpu, data);
+        }
+        unsafe { remove_trampoline() };
+    }
+
+    log::info!("{} of {} cpus online", online_cpus(), all.len());
+}
+
+
+unsafe fn install_trampoline() -> *mut TrampolineData {
+    // identity mapped, the ap keeps executing it at the same address once paging is on
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(AP_TRAMPOLINE_BASE));
+    let frame = PhysFrame::containing_address(PhysAddr::new(AP_TRAMPOLINE_BASE));
+    paging::map(page, frame, PageTableFlags::empty()).expect("failed to map the ap trampoline");
+
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!(len <= 4096, "ap trampoline doesn't fit in a page");
+
+    let destination = phys_to_virt(PhysAddr::new(AP_TRAMPOLINE_BASE)).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(start, destination, len);
+
+    let data_offset = &ap_trampoline_data as *const u8 as usize - start as usize;
+    destination.add(data_offset) as *mut TrampolineData
+}
+
+unsafe fn remove_trampoline() {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(AP_TRAMPOLINE_BASE));
+    // the frame is in low memory, it never belonged to the frame allocator
+    paging::unmap(page).expect("failed to unmap the ap trampoline");
+}
+
+
+/// INIT-SIPI-SIPI, the cpus are started one at a time since they share the trampoline data.
+fn start_ap(cpu: &Cpu, data: *mut TrampolineData) {
+    let (cr3, _) = Cr3::read();
+    let cr3 = cr3.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "the kernel page tables have to be below 4 GiB for the ap trampoline");
+
+    // the cpu runs on it for good, its idle thread keeps using it
+    let stack = Stack::new(AP_STACK_SIZE).expect("no memory for an ap stack");
+    let stack_top = stack.top().as_u64();
+    core::mem::forget(stack);
+
+    unsafe {
+        data.write_volatile(TrampolineData {
+            cr3,
+            stack: stack_top,
+            entry: ap_entry as extern "C" fn(u64) -> ! as usize as u64,
+            cpu: cpu.index as u64,
+        });
+
+        let apic = &mut *core::ptr::addr_of_mut!(LOCAL_APIC);
+        let destination = apic.destination(cpu.apic_id);
+
+        apic.ipi_init(destination);
+        if !apic.is_x2apic() {
+            apic.ipi_init_deassert();
+        }
+        time::spin_wait(Duration::from_millis(10));
+
+        // the second startup ipi is only needed when the first one got lost
+        let vector = (AP_TRAMPOLINE_BASE >> 12) as u8;
+        for timeout in [Duration::from_millis(1), AP_STARTUP_TIMEOUT] {
+            apic.ipi_startup(destination, vector);
+            if wait_online(cpu, timeout) {
+                return;
+            }
+        }
+    }
+
+    log::warn!("cpu {} (apic id {}) didn't start", cpu.index, cpu.apic_id);
+}
+
+fn wait_online(cpu: &Cpu, timeout: Duration) -> bool {
+    let end = time::now() + timeout.as_nanos() as u64;
+    while time::now() < end {
+        if cpu.is_online() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    cpu.is_online()
+}
+
+
+/// Called by the trampoline in long mode on the cpu's own stack.
+extern "C" fn ap_entry(index: u64) -> ! {
+    super::percpu::init(index as usize);
+    gdt::init_ap();
+    interrupts::idt::init_ap();
+    unsafe {
+        (*core::ptr::addr_of_mut!(LOCAL_APIC)).init_ap();
+    }
+    time::init_ap();
+    thread::init();
+
+    cpus()[index as usize].set_online();
+    x86_64::instructions::interrupts::enable();
+
+    thread::spawn(|| Executor::new().run());
+    thread::idle()
+}
@@ -0,0 +1,101 @@
+use alloc::boxed::Box;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_SIZE: usize = 5 * 4096;
+
+
+/// GDT and TSS of one CPU.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+    selectors: Selectors,
+}
+
+#[derive(Clone, Copy)]
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+impl CpuTables {
+    /// `ist_stacks` are the tops of the double fault, NMI and machine check stacks.
+    fn new(ist_stacks: [VirtAddr; 3]) -> Self {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stacks[0];
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stacks[1];
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stacks[2];
+
+        Self {
+            gdt: GlobalDescriptorTable::new(),
+            tss,
+            // filled in by `load` once the TSS has its final address
+            selectors: Selectors {
+                code: SegmentSelector(0),
+                data: SegmentSelector(0),
+                tss: SegmentSelector(0),
+            },
+        }
+    }
+
+    fn load(&'static mut self) {
+        let tss: &'static TaskStateSegment = unsafe { &*(&self.tss as *const TaskStateSegment) };
+        self.selectors = Selectors {
+            code: self.gdt.add_entry(Descriptor::kernel_code_segment()),
+            data: self.gdt.add_entry(Descriptor::kernel_data_segment()),
+            tss: self.gdt.add_entry(Descriptor::tss_segment(tss)),
+        };
+
+        let this: &'static Self = self;
+        this.gdt.load();
+        unsafe {
+            CS::set_reg(this.selectors.code);
+            SS::set_reg(this.selectors.data);
+            DS::set_reg(this.selectors.data);
+            ES::set_reg(this.selectors.data);
+            load_tss(this.selectors.tss);
+        }
+    }
+}
+
+
+// the BSP can't use the heap, it loads its GDT before memory is set up
+static mut BSP_IST_STACKS: [[u8; IST_STACK_SIZE]; 3] = [[0; IST_STACK_SIZE]; 3];
+static mut BSP_TABLES: Option<CpuTables> = None;
+
+
+/// Replaces the boot GDT from `entry.asm` on the BSP.
+pub fn init() {
+    let stacks = unsafe {
+        let stacks = &*core::ptr::addr_of!(BSP_IST_STACKS);
+        stacks.each_ref().map(|stack| VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE)
+    };
+
+    let tables = unsafe { &mut *core::ptr::addr_of_mut!(BSP_TABLES) };
+    tables.insert(CpuTables::new(stacks)).load();
+}
+
+/// Loads a fresh GDT and TSS on an application processor, the stacks come from the heap.
+pub fn init_ap() {
+    let stacks = [(); 3].map(|_| {
+        let stack: &'static mut [u8] = Box::leak(alloc::vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+        VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE
+    });
+
+    Box::leak(Box::new(CpuTables::new(stacks))).load();
+}
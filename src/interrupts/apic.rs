@@ -1,84 +1,272 @@
+use crate::{memory, CPUID};
 use pic8259::ChainedPics;
-use x86::apic::{xapic, ApicControl};
-use core::{mem::ManuallyDrop, ops::{Deref, DerefMut}, ptr::{read_volatile, write_volatile}};
+use x86::apic::{
+    x2apic, xapic, ApicControl, ApicId, DeliveryMode, DeliveryStatus, DestinationMode,
+    DestinationShorthand, Icr, Level, TriggerMode as IpiTriggerMode,
+};
+use x86::msr::{rdmsr, wrmsr};
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::PhysAddr;
 
 
 pub static mut PICS: ChainedPics = unsafe { ChainedPics::new(32, 8) };
-pub static mut LOCAL_APIC: LocalApic = LocalApic { public: PublicXapic::empty() };
+pub static mut LOCAL_APIC: LocalApic = LocalApic::Uninit;
 
+/// The timer runs at the bus clock divided by this.
+pub const TIMER_DIVIDER: u32 = 16;
+// divide configuration register encoding of `TIMER_DIVIDER`
+const TIMER_DIVIDE_CONFIG: u32 = 0b0011;
 
-pub union LocalApic {
-    apic: ManuallyDrop<xapic::XAPIC>,
-    public: PublicXapic,
+// x2APIC registers are MSRs at this base plus the xAPIC offset divided by 16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+
+/// The local APIC of the current CPU, in whichever mode the CPU supports.
+///
+/// x2APIC is used when CPUID advertises it, it is accessed through MSRs and has 32 bit APIC IDs.
+pub enum LocalApic {
+    Uninit,
+    XApic {
+        apic: xapic::XAPIC,
+        mmio: u64,
+    },
+    X2Apic(x2apic::X2APIC),
 }
 
 impl LocalApic {
-    pub fn get(&self) -> &xapic::XAPIC {
-        unsafe { self.apic.deref() }
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self, LocalApic::X2Apic(_))
     }
 
-    pub fn get_mut(&mut self) -> &mut xapic::XAPIC {
-        unsafe { self.apic.deref_mut() }
+    /// APIC ID of the current CPU, above 255 is only possible in x2APIC mode.
+    pub fn apic_id(&self) -> u32 {
+        self.id()
     }
 
-    pub unsafe fn init(&mut self, mimo: *mut u32) {
+    /// Disables the PICs and attaches to the local APIC, `addr` is only mapped in xAPIC mode.
+    pub unsafe fn init(&mut self, addr: PhysAddr) {
         PICS.initialize();
         PICS.disable();
 
-        let mut local_apic = xapic::XAPIC::new(core::slice::from_raw_parts_mut(mimo, 256));
-        local_apic.attach();
-        *self.get_mut() = local_apic;
+        let x2apic = CPUID.get_feature_info().map_or(false, |info| info.has_x2apic());
+        *self = match x2apic {
+            true => {
+                let mut apic = x2apic::X2APIC::new();
+                apic.attach();
+                LocalApic::X2Apic(apic)
+            }
+            false => {
+                let mmio = memory::map_mmio(addr, 4096);
+                let mut apic = xapic::XAPIC::new(core::slice::from_raw_parts_mut(mmio.as_mut_ptr(), 256));
+                apic.attach();
+                LocalApic::XApic { apic, mmio: mmio.as_u64() }
+            }
+        };
 
         x86_64::instructions::interrupts::enable();
     }
 
-    pub fn enable_timer(&mut self) {
+    /// Attaches an application processor to its own local APIC, the mode was chosen by the BSP.
+    pub unsafe fn init_ap(&mut self) {
+        if let LocalApic::X2Apic(_) = self {
+            // IA32_APIC_BASE is per CPU, every one has to switch to x2APIC mode
+            x2apic::X2APIC::new().attach();
+        }
+        self.enable();
+    }
+
+    fn control(&self) -> &dyn ApicControl {
+        match self {
+            LocalApic::Uninit => panic!("local apic not initialized"),
+            LocalApic::XApic { apic, .. } => apic,
+            LocalApic::X2Apic(apic) => apic,
+        }
+    }
+
+    fn control_mut(&mut self) -> &mut dyn ApicControl {
+        match self {
+            LocalApic::Uninit => panic!("local apic not initialized"),
+            LocalApic::XApic { apic, .. } => apic,
+            LocalApic::X2Apic(apic) => apic,
+        }
+    }
+
+    /// `offset` is the xAPIC MMIO offset, it is translated to the MSR in x2APIC mode.
+    unsafe fn read_reg(&self, offset: u32) -> u32 {
+        match self {
+            LocalApic::Uninit => panic!("local apic not initialized"),
+            LocalApic::XApic { mmio, .. } => read_volatile((mmio + offset as u64) as *const u32),
+            LocalApic::X2Apic(_) => rdmsr(X2APIC_MSR_BASE + (offset >> 4)) as u32,
+        }
+    }
+
+    unsafe fn write_reg(&mut self, offset: u32, value: u32) {
+        match self {
+            LocalApic::Uninit => panic!("local apic not initialized"),
+            LocalApic::XApic { mmio, .. } => write_volatile((*mmio + offset as u64) as *mut u32, value),
+            LocalApic::X2Apic(_) => wrmsr(X2APIC_MSR_BASE + (offset >> 4), value as u64),
+        }
+    }
+
+    /// Software enables the APIC and checks that it is an integrated one.
+    pub fn enable(&mut self) {
         unsafe {
-            let mimo_addr = self.public.mimo_region_ptr;
-            
-            let svr = read_volatile((mimo_addr + xapic::XAPIC_SVR as u64) as *const u32);
-            write_volatile((mimo_addr + xapic::XAPIC_SVR as u64) as *mut u32, svr | 0x100);
-
-            // set to periodic mode and map to 32nd idt entry
-            write_volatile(
-                (mimo_addr + xapic::XAPIC_LVT_TIMER as u64) as *mut u32,
-                super::InterruptIndex::APICTimer as u32 | (1 << 17)
-            );
-
-            // timer divider
-            write_volatile((mimo_addr + xapic::XAPIC_TIMER_DIV_CONF as u64) as *mut u32, 0b1010);
-            
-            // timer count
-                write_volatile((mimo_addr + xapic::XAPIC_TIMER_INIT_COUNT as u64) as *mut u32, 0x200000);
-
-            let apic_version = LOCAL_APIC.get().version() & 0xFF;
-            match apic_version {
-                0x0..=0xF => {
-                    todo!("Local APIC is 82489DX discrete APIC");
-                },
-                0x10..=0x15 => {
-                    // todo!("Integrated APIC");
-                },
-                _ => unreachable!("Reserved value"),
+            // the spurious vector has no handler, it is dropped in `irq_dispatch`
+            let svr = self.read_reg(xapic::XAPIC_SVR) & !0xFF;
+            self.write_reg(xapic::XAPIC_SVR, svr | 0x100 | super::handler::SPURIOUS_VECTOR as u32);
+        }
+
+        let apic_version = self.version() & 0xFF;
+        match apic_version {
+            0x0..=0xF => {
+                todo!("Local APIC is 82489DX discrete APIC");
+            },
+            0x10..=0x15 => {
+                // todo!("Integrated APIC");
+            },
+            _ => unreachable!("Reserved value"),
+        }
+    }
+
+    /// Starts the timer counting down from `initial_count` at the bus clock divided by `TIMER_DIVIDER`.
+    ///
+    /// A masked timer still counts but never raises the interrupt, this is used for calibration.
+    /// In `TscDeadline` mode `initial_count` is ignored, the timer is armed with `set_tsc_deadline`.
+    pub fn start_timer(&mut self, mode: TimerMode, initial_count: u32, masked: bool) {
+        let mut lvt = super::InterruptIndex::APICTimer as u32;
+        match mode {
+            TimerMode::OneShot => {}
+            TimerMode::Periodic => lvt |= 0b01 << 17,
+            TimerMode::TscDeadline => lvt |= 0b10 << 17,
+        }
+        if masked {
+            lvt |= 1 << 16;
+        }
+
+        unsafe {
+            self.write_reg(xapic::XAPIC_LVT_TIMER, lvt);
+            if mode == TimerMode::TscDeadline {
+                // the mode switch has to land before the first write to the deadline msr
+                core::arch::x86_64::_mm_mfence();
+                return;
             }
+            self.write_reg(xapic::XAPIC_TIMER_DIV_CONF, TIMER_DIVIDE_CONFIG);
+            // writing the initial count (re)starts the timer
+            self.write_reg(xapic::XAPIC_TIMER_INIT_COUNT, initial_count);
         }
     }
-}
 
-#[derive(Clone, Copy)]
-struct PublicXapic {
-    mimo_region_ptr: u64,
-    mimo_region_lenght: u64,
-    base: u64,
+    /// Fires the timer once the TSC reaches `deadline`, 0 disarms it.
+    ///
+    /// Only valid after `start_timer` with `TimerMode::TscDeadline`.
+    pub fn set_tsc_deadline(&mut self, deadline: u64) {
+        unsafe {
+            wrmsr(x86::msr::IA32_TSC_DEADLINE, deadline);
+        }
+    }
+
+    pub fn stop_timer(&mut self) {
+        unsafe {
+            self.write_reg(xapic::XAPIC_TIMER_INIT_COUNT, 0);
+        }
+    }
+
+    pub fn timer_initial_count(&self) -> u32 {
+        unsafe { self.read_reg(xapic::XAPIC_TIMER_INIT_COUNT) }
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read_reg(xapic::XAPIC_TIMER_CURRENT_COUNT) }
+    }
+
+    /// Destination of an IPI in the format of the current mode.
+    pub fn destination(&self, apic_id: u32) -> ApicId {
+        match self.is_x2apic() {
+            true => ApicId::X2Apic(apic_id),
+            false => ApicId::XApic(apic_id as u8),
+        }
+    }
+
+    /// Builds an ICR value in the format of the current mode.
+    pub fn icr(&self, vector: u8, destination: u32, shorthand: DestinationShorthand, delivery_mode: DeliveryMode) -> Icr {
+        let build = match self.is_x2apic() {
+            true => Icr::for_x2apic,
+            false => Icr::for_xapic,
+        };
+        build(
+            vector,
+            self.destination(destination),
+            shorthand,
+            delivery_mode,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            IpiTriggerMode::Edge,
+        )
+    }
+
+    /// Sends a fixed interrupt with `vector` to the CPU with `apic_id`.
+    pub unsafe fn send_fixed_ipi(&mut self, apic_id: u32, vector: u8) {
+        let icr = self.icr(vector, apic_id, DestinationShorthand::NoShorthand, DeliveryMode::Fixed);
+        self.send_ipi(icr);
+    }
 }
 
-impl PublicXapic {
-    const fn empty() -> Self {
-        Self {
-            mimo_region_ptr: 0,
-            mimo_region_lenght: 0,
-            base: 0,
+/// The xAPIC reports its ID in the top byte of the register, it is shifted down here
+/// so both modes return the plain ID.
+impl ApicControl for LocalApic {
+    fn bsp(&self) -> bool {
+        self.control().bsp()
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic { apic, .. } => apic.id() >> 24,
+            _ => self.control().id(),
         }
     }
+
+    fn logical_id(&self) -> u32 {
+        self.control().logical_id()
+    }
+
+    fn version(&self) -> u32 {
+        self.control().version()
+    }
+
+    fn eoi(&mut self) {
+        self.control_mut().eoi()
+    }
+
+    fn tsc_enable(&mut self, vector: u8) {
+        self.control_mut().tsc_enable(vector)
+    }
+
+    fn tsc_set(&self, value: u64) {
+        self.control().tsc_set(value)
+    }
+
+    unsafe fn ipi_init(&mut self, core: ApicId) {
+        self.control_mut().ipi_init(core)
+    }
+
+    unsafe fn ipi_init_deassert(&mut self) {
+        self.control_mut().ipi_init_deassert()
+    }
+
+    unsafe fn ipi_startup(&mut self, core: ApicId, start_page: u8) {
+        self.control_mut().ipi_startup(core, start_page)
+    }
+
+    unsafe fn send_ipi(&mut self, icr: Icr) {
+        self.control_mut().send_ipi(icr)
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+    /// Needs CPUID.01H:ECX.TSC_Deadline.
+    TscDeadline,
+}
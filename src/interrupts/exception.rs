@@ -0,0 +1,234 @@
+use super::InterruptIndex;
+use crate::{println, symbols};
+use core::arch::global_asm;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+
+/// Registers saved by the exception stubs, in the order they are on the stack.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    pub vector: u64,
+    // 0 for exceptions that don't push one
+    pub error_code: u64,
+
+    // pushed by the cpu
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+
+// one stub per vector, the ones without an error code push a 0 so every frame looks the same
+global_asm!(r#"
+.macro exception_stub vector, has_error_code
+exception_stub_\vector:
+.if \has_error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+.section .text
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 15, 0
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 22, 0
+exception_stub 23, 0
+exception_stub 24, 0
+exception_stub 25, 0
+exception_stub 26, 0
+exception_stub 27, 0
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+exception_stub 31, 0
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    cld
+    call {dispatch}
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    // vector and error code
+    add rsp, 16
+    iretq
+
+.section .rodata
+.balign 8
+.global exception_stubs
+exception_stubs:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_stub_\vector
+.endr
+"#, dispatch = sym exception_dispatch);
+
+extern "C" {
+    /// Entry points for vectors 0 to 31.
+    pub static exception_stubs: [u64; 32];
+}
+
+
+extern "C" fn exception_dispatch(frame: &mut TrapFrame) {
+    let index = InterruptIndex::from_vector(frame.vector as u8);
+
+    match index {
+        // traps, execution can continue after the instruction
+        Some(InterruptIndex::Breakpoint) | Some(InterruptIndex::Debug) => {
+            println!("EXCEPTION: {:?} at {:#X}", index.unwrap(), frame.rip);
+        }
+        // another cpu is panicking
+        Some(InterruptIndex::NonMaskableInterrupt) if super::ipi::halt_requested() => {
+            super::ipi::halt_this_cpu();
+        }
+        _ => {
+            // the exception may have hit while the console lock was held
+            crate::begin_panic();
+            print_crash_report(frame);
+            match index {
+                Some(index) => panic!("EXCEPTION: {:?}", index),
+                None => panic!("EXCEPTION: reserved vector {}", frame.vector),
+            }
+        }
+    }
+}
+
+
+fn print_crash_report(frame: &TrapFrame) {
+    let index = InterruptIndex::from_vector(frame.vector as u8);
+
+    println!("========== CRASH REPORT ==========");
+    match index {
+        Some(index) => println!("exception: {:?} (vector {})", index, frame.vector),
+        None => println!("exception: reserved (vector {})", frame.vector),
+    }
+    print_error_code(index, frame.error_code);
+
+    match symbols::lookup(frame.rip) {
+        Some(symbol) => println!("RIP: {:#018X} <{}>", frame.rip, symbol),
+        None => println!("RIP: {:#018X}", frame.rip),
+    }
+    println!("CS:  {:#06X}  SS: {:#06X}  RFLAGS: {:#018X}", frame.cs, frame.ss, frame.rflags);
+    println!("RSP: {:#018X}  RBP: {:#018X}", frame.rsp, frame.rbp);
+    println!("RAX: {:#018X}  RBX: {:#018X}  RCX: {:#018X}", frame.rax, frame.rbx, frame.rcx);
+    println!("RDX: {:#018X}  RSI: {:#018X}  RDI: {:#018X}", frame.rdx, frame.rsi, frame.rdi);
+    println!("R8:  {:#018X}  R9:  {:#018X}  R10: {:#018X}", frame.r8, frame.r9, frame.r10);
+    println!("R11: {:#018X}  R12: {:#018X}  R13: {:#018X}", frame.r11, frame.r12, frame.r13);
+    println!("R14: {:#018X}  R15: {:#018X}", frame.r14, frame.r15);
+    println!("CR0: {:#018X}  CR2: {:#018X}", Cr0::read_raw(), Cr2::read().as_u64());
+    println!("CR3: {:#018X}  CR4: {:#018X}", Cr3::read_raw().0.start_address().as_u64(), Cr4::read_raw());
+    println!("==================================");
+}
+
+fn print_error_code(index: Option<InterruptIndex>, error_code: u64) {
+    use InterruptIndex::*;
+
+    match index {
+        Some(PageFault) => {
+            let flags = PageFaultErrorCode::from_bits_truncate(error_code);
+            println!("error code: {:#X} {:?}", error_code, flags);
+        }
+        // selector error code
+        Some(InvalidTSS) | Some(SegmentNotPResent) | Some(StackSegmentFault) | Some(GeneralProtectionFault) => {
+            if error_code == 0 {
+                println!("error code: 0");
+                return;
+            }
+            let table = match (error_code >> 1) & 0b11 {
+                0b00 => "GDT",
+                0b10 => "LDT",
+                _ => "IDT",
+            };
+            println!(
+                "error code: {:#X} (selector index {} in {}, external: {})",
+                error_code, (error_code >> 3) & 0x1FFF, table, error_code & 1 == 1,
+            );
+        }
+        Some(ControlProtection) => {
+            let cause = match error_code & 0x7FFF {
+                1 => "NEAR-RET",
+                2 => "FAR-RET/IRET",
+                3 => "ENDBRANCH",
+                4 => "RSTORSSP",
+                5 => "SETSSBSY",
+                _ => "unknown",
+            };
+            println!("error code: {:#X} ({}, enclave: {})", error_code, cause, error_code & (1 << 15) != 0);
+        }
+        Some(VmmCommunication) => println!("error code: {:#X} (exit code)", error_code),
+        Some(DoubleFault) | Some(AlignmentCheck) | Some(SecurityException) => {
+            println!("error code: {:#X}", error_code);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,278 @@
+use super::exception::TrapFrame;
+use super::routing::{self, Polarity, TriggerMode};
+use super::InterruptIndex;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86::apic::ApicControl;
+
+
+/// First vector the PICs are remapped to, their 16 vectors are never handed out.
+const PIC_VECTOR_BASE: u8 = 32;
+pub(super) const SPURIOUS_VECTOR: u8 = 0xFF;
+const MAX_SHARED_HANDLERS: usize = 8;
+// every stub is aligned to this many bytes, so the IDT can compute their addresses
+const IRQ_STUB_SIZE: u64 = 16;
+
+
+pub type Handler = fn(&mut TrapFrame);
+
+/// What a handler is attached to.
+#[derive(Debug, Clone, Copy)]
+pub enum Irq {
+    /// A fixed IDT vector, used for the LAPIC timer and IPIs.
+    Vector(u8),
+    /// An I/O APIC input, a free vector is allocated and the line is routed to it.
+    Gsi(u32, Polarity, TriggerMode),
+    /// An ISA IRQ, resolved to a GSI through the MADT overrides.
+    Isa(u8),
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    /// Vectors below 32 are exceptions.
+    InvalidVector,
+    NoFreeVector,
+    /// The vector or GSI already has a handler and can't be shared.
+    Busy,
+    TooManyHandlers,
+}
+
+/// Returned by `register`, hand it to `unregister` to remove the handler again.
+#[derive(Debug)]
+#[must_use]
+pub struct Registration {
+    vector: u8,
+    id: u64,
+}
+
+impl Registration {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+
+#[derive(Clone, Copy)]
+struct VectorSlot {
+    handlers: [Option<(u64, Handler)>; MAX_SHARED_HANDLERS],
+    // set when the vector was allocated for an I/O APIC line
+    gsi: Option<u32>,
+    shared: bool,
+}
+
+impl VectorSlot {
+    const EMPTY: Self = Self {
+        handlers: [None; MAX_SHARED_HANDLERS],
+        gsi: None,
+        shared: false,
+    };
+
+    fn is_free(&self) -> bool {
+        self.gsi.is_none() && self.handlers.iter().all(Option::is_none)
+    }
+
+    fn add(&mut self, id: u64, handler: Handler) -> Result<(), RegisterError> {
+        let slot = self.handlers.iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(RegisterError::TooManyHandlers)?;
+        *slot = Some((id, handler));
+        Ok(())
+    }
+}
+
+static VECTORS: RwLock<[VectorSlot; 224]> = RwLock::new([VectorSlot::EMPTY; 224]);
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
+
+global_asm!(r#"
+.section .text
+.balign 16
+.global irq_stubs
+irq_stubs:
+.set irq_vector, 32
+.rept 224
+.balign 16
+    push 0
+    push irq_vector
+    jmp irq_common
+.set irq_vector, irq_vector + 1
+.endr
+
+irq_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    cld
+    call {dispatch}
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    add rsp, 16
+    iretq
+"#, dispatch = sym irq_dispatch);
+
+extern "C" {
+    static irq_stubs: u8;
+}
+
+/// Entry point of the stub for a vector in 32..=255.
+pub(super) fn stub_address(vector: u8) -> u64 {
+    let base = unsafe { &irq_stubs as *const u8 as u64 };
+    base + (vector - 32) as u64 * IRQ_STUB_SIZE
+}
+
+
+extern "C" fn irq_dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let percpu = crate::cpu::percpu::current();
+    percpu.enter_interrupt();
+
+    // copied out so a handler can't deadlock by (un)registering
+    let slot = VECTORS.read()[(vector - 32) as usize];
+    let mut handled = false;
+    for (_, handler) in slot.handlers.iter().flatten() {
+        handler(frame);
+        handled = true;
+    }
+
+    if !handled && vector != SPURIOUS_VECTOR {
+        log::warn!("unhandled interrupt vector {}", vector);
+    }
+
+    end_of_interrupt(vector);
+    percpu.leave_interrupt();
+
+    // back to thread context, the interrupted thread may have used up its time slice
+    if percpu.interrupt_depth() == 0 {
+        crate::thread::preempt();
+    }
+}
+
+fn end_of_interrupt(vector: u8) {
+    match vector {
+        SPURIOUS_VECTOR => {}
+        PIC_VECTOR_BASE..=47 => unsafe {
+            super::apic::PICS.notify_end_of_interrupt(vector);
+        },
+        _ => unsafe {
+            super::apic::LOCAL_APIC.eoi();
+        },
+    }
+}
+
+
+fn is_reserved(vector: u8) -> bool {
+    vector < 48 || vector == SPURIOUS_VECTOR || InterruptIndex::from_vector(vector).is_some()
+}
+
+fn with_vectors<R>(f: impl FnOnce(&mut [VectorSlot; 224]) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut VECTORS.write()))
+}
+
+/// Attaches `handler` to an interrupt, the EOI is sent automatically once all handlers of the vector ran.
+///
+/// Level triggered GSIs can be shared by up to 8 handlers, everything else is exclusive.
+pub fn register(irq: Irq, handler: Handler) -> Result<Registration, RegisterError> {
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+
+    match irq {
+        Irq::Vector(vector) => {
+            if vector < 32 {
+                return Err(RegisterError::InvalidVector);
+            }
+            with_vectors(|vectors| {
+                let slot = &mut vectors[(vector - 32) as usize];
+                if !slot.is_free() {
+                    return Err(RegisterError::Busy);
+                }
+                slot.add(id, handler)?;
+                Ok(Registration { vector, id })
+            })
+        }
+        Irq::Isa(irq) => {
+            let route = routing::isa_route(irq);
+            register(Irq::Gsi(route.gsi, route.polarity, route.trigger_mode), handler)
+        }
+        Irq::Gsi(gsi, polarity, trigger_mode) => {
+            let (vector, new) = with_vectors(|vectors| {
+                // chain onto an existing level triggered line
+                if let Some(index) = vectors.iter().position(|s| s.gsi == Some(gsi)) {
+                    let slot = &mut vectors[index];
+                    if !slot.shared || trigger_mode != TriggerMode::Level {
+                        return Err(RegisterError::Busy);
+                    }
+                    slot.add(id, handler)?;
+                    return Ok((index as u8 + 32, false));
+                }
+
+                let index = (0..224)
+                    .find(|&i| !is_reserved(i as u8 + 32) && vectors[i].is_free())
+                    .ok_or(RegisterError::NoFreeVector)?;
+                let slot = &mut vectors[index];
+                slot.gsi = Some(gsi);
+                slot.shared = trigger_mode == TriggerMode::Level;
+                slot.add(id, handler)?;
+                Ok((index as u8 + 32, true))
+            })?;
+
+            if new {
+                routing::route_gsi(gsi, vector, polarity, trigger_mode);
+            }
+            Ok(Registration { vector, id })
+        }
+    }
+}
+
+/// Removes a handler, the vector is freed and its line masked once no handler is left.
+pub fn unregister(registration: Registration) {
+    let freed_gsi = with_vectors(|vectors| {
+        let slot = &mut vectors[(registration.vector - 32) as usize];
+        for handler in slot.handlers.iter_mut() {
+            if matches!(handler, Some((id, _)) if *id == registration.id) {
+                *handler = None;
+            }
+        }
+
+        match slot.handlers.iter().all(Option::is_none) {
+            true => {
+                let gsi = slot.gsi;
+                *slot = VectorSlot::EMPTY;
+                gsi
+            }
+            false => None,
+        }
+    });
+
+    if let Some(gsi) = freed_gsi {
+        routing::mask_gsi(gsi);
+    }
+}
@@ -1,11 +1,12 @@
-use crate::{print, println};
-use core::arch::asm;
+use super::exception::TrapFrame;
+use super::handler::{self, Irq};
+use crate::{gdt, print};
 use lazy_static::lazy_static;
-use x86::apic::ApicControl;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
+use x86_64::VirtAddr;
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum InterruptIndex {
@@ -29,131 +30,158 @@ pub enum InterruptIndex {
     AlignmentCheck,
     MachineCheck,
     SimdFloatingPoint,
-    
+    VirtualizationException,
+    ControlProtection,
+    // 22-27 are reserved
+    HypervisorInjection = 28,
+    VmmCommunication,
+    SecurityException,
+    // 31 is reserved
+
     // PIC 8259
     PicTimer = 32,
     Keyboard,
 
     // APIC
     APICTimer = 48,
-}
-
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
-
-        idt[InterruptIndex::PicTimer as usize].set_handler_fn(pic_timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
-
-        idt[InterruptIndex::APICTimer as usize].set_handler_fn(apic_timer_interrupt_handler);
-        idt
-    };
+    // IPIs, see `ipi`
+    IpiReschedule = 0xF0,
+    IpiCall,
+    IpiTlbShootdown,
 }
 
-pub fn init() {
-    IDT.load();
-}
 
-
-extern "x86-interrupt" fn breakpoint_handler (
-    _stack_frame: InterruptStackFrame
-) {
-    println!("EXCEPTION: BREAKPOINT");
-    unsafe { asm!("hlt") };
+impl InterruptIndex {
+    /// Returns `None` for reserved exception vectors and vectors without a fixed purpose.
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        use InterruptIndex::*;
+
+        Some(match vector {
+            0 => DivideError,
+            1 => Debug,
+            2 => NonMaskableInterrupt,
+            3 => Breakpoint,
+            4 => Overflow,
+            5 => BoundRangeExceeded,
+            6 => InvalidOpcode,
+            7 => DeviceNotAvailable,
+            8 => DoubleFault,
+            9 => CoprocessorSegmentOverrun,
+            10 => InvalidTSS,
+            11 => SegmentNotPResent,
+            12 => StackSegmentFault,
+            13 => GeneralProtectionFault,
+            14 => PageFault,
+            16 => X87FloatingPoint,
+            17 => AlignmentCheck,
+            18 => MachineCheck,
+            19 => SimdFloatingPoint,
+            20 => VirtualizationException,
+            21 => ControlProtection,
+            28 => HypervisorInjection,
+            29 => VmmCommunication,
+            30 => SecurityException,
+            32 => PicTimer,
+            33 => Keyboard,
+            48 => APICTimer,
+            0xF0 => IpiReschedule,
+            0xF1 => IpiCall,
+            0xF2 => IpiTlbShootdown,
+            _ => return None,
+        })
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    panic!("EXCEPTION: PAGE FAULT (0x{:0X})\n{:#?}", error_code, stack_frame);
-}
 
-#[no_mangle]
-extern "x86-interrupt" fn double_fault_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT (0x{:0X})\n{:#?}", error_code, stack_frame);
+/// Returns the raw entry of any vector, including the reserved exceptions
+/// `InterruptDescriptorTable` doesn't give access to.
+fn entry_mut(idt: &mut InterruptDescriptorTable, vector: u8) -> &mut Entry<HandlerFunc> {
+    // the table is 256 entries of the same layout
+    unsafe {
+        let entries = idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFunc>;
+        &mut *entries.add(vector as usize)
+    }
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("EXCEPTION: STACK SEGMENT FAULT (0x{:0X})\n{:#?}", error_code, stack_frame);
-}
 
-extern "x86-interrupt" fn general_protection_fault_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT (0x{:0X})\n{:#?}", error_code, stack_frame);
-}
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        for vector in 0..32 {
+            unsafe {
+                let stub = super::exception::exception_stubs[vector as usize];
+                let options = entry_mut(&mut idt, vector).set_handler_addr(VirtAddr::new(stub));
+
+                // these can hit while the current stack is unusable
+                match InterruptIndex::from_vector(vector) {
+                    Some(InterruptIndex::DoubleFault) => {
+                        options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+                    }
+                    Some(InterruptIndex::NonMaskableInterrupt) => {
+                        options.set_stack_index(gdt::NMI_IST_INDEX);
+                    }
+                    Some(InterruptIndex::MachineCheck) => {
+                        options.set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for vector in 32..=255u8 {
+            unsafe {
+                entry_mut(&mut idt, vector).set_handler_addr(VirtAddr::new(handler::stub_address(vector)));
+            }
+        }
 
-extern "x86-interrupt" fn divide_error_handler (
-    stack_frame: InterruptStackFrame,
-) {
-    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+        idt
+    };
 }
 
-extern "x86-interrupt" fn invalid_tss_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("EXCEPTION: INVALID TSS (0x{:0X})\n{:#?}", error_code, stack_frame);
+pub fn init() {
+    IDT.load();
+
+    let _ = handler::register(Irq::Vector(InterruptIndex::PicTimer as u8), pic_timer_interrupt_handler).unwrap();
+    let _ = handler::register(Irq::Vector(InterruptIndex::APICTimer as u8), apic_timer_interrupt_handler).unwrap();
 }
 
-extern "x86-interrupt" fn segment_not_present_handler (
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("EXCEPTION: SEGMENT NOT PRESENT (0x{:0X})\n{:#?}", error_code, stack_frame);
+/// Loads the shared IDT on an application processor, the handlers are already registered.
+pub fn init_ap() {
+    IDT.load();
 }
 
 
-extern "x86-interrupt" fn pic_timer_interrupt_handler (
-    _stack_frame: InterruptStackFrame)
-{
+fn pic_timer_interrupt_handler(_frame: &mut TrapFrame) {
     print!("_");
-    unsafe {
-        super::apic::PICS
-            .notify_end_of_interrupt(InterruptIndex::PicTimer as u8);
-    }
 }
 
 
-extern "x86-interrupt" fn keyboard_interrupt_handler (
-    _stack_frame: InterruptStackFrame)
-{
+pub(crate) fn keyboard_interrupt_handler(_frame: &mut TrapFrame) {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
+}
 
-    unsafe {
-        super::apic::PICS
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+pub(crate) fn serial_interrupt_handler(_frame: &mut TrapFrame) {
+    let (_, uart) = match crate::com::shell_port() {
+        Some(port) => port,
+        None => return,
+    };
+    // drain the fifo, the interrupt only fires again once it refilled
+    while let Some(byte) = uart.read_byte() {
+        crate::task::serial::add_byte(byte);
     }
 }
 
 
-
-extern "x86-interrupt" fn apic_timer_interrupt_handler (
-    _stack_frame: InterruptStackFrame)
-{
-    print!(".");
-    unsafe {
-        super::apic::LOCAL_APIC.get_mut().eoi();
+fn apic_timer_interrupt_handler(_frame: &mut TrapFrame) {
+    // only the bsp keeps time, every cpu time-slices its threads
+    if crate::cpu::current_index() == 0 {
+        crate::time::tick();
+        crate::task::time::on_tick();
     }
+    crate::thread::on_timer();
 }
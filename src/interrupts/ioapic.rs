@@ -96,6 +96,9 @@ pub struct IoApic {
     win: *mut u32,
 }
 
+// only ever accessed behind the router lock
+unsafe impl Send for IoApic {}
+
 impl IoApic {
     pub unsafe fn new(addr: usize) -> Self {
         Self {
@@ -0,0 +1,211 @@
+use super::apic::LOCAL_APIC;
+use super::exception::TrapFrame;
+use super::handler::{self, Irq};
+use super::InterruptIndex;
+use crate::cpu::{self, percpu};
+use crate::memory::paging;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86::apic::{ApicControl, DeliveryMode, DestinationShorthand};
+use x86_64::instructions::{interrupts::without_interrupts, tlb};
+use x86_64::VirtAddr;
+
+
+// above this many pages the whole TLB is flushed instead
+const MAX_INVLPG_PAGES: u64 = 32;
+// spins the panicking cpu waits for the others to halt, the timer may be unusable by then
+const HALT_WAIT_SPINS: usize = 10_000_000;
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    /// The CPU with this APIC ID.
+    Cpu(u32),
+    AllButSelf,
+    All,
+}
+
+#[derive(Debug)]
+pub enum IpiError {
+    NoSuchCpu,
+    /// The call queue of the target CPU is full.
+    QueueFull,
+}
+
+
+/// Registers the IPI handlers and takes over TLB shootdowns from the pager.
+pub fn init() {
+    let handlers: [(InterruptIndex, handler::Handler); 3] = [
+        (InterruptIndex::IpiReschedule, reschedule_handler),
+        (InterruptIndex::IpiCall, call_handler),
+        (InterruptIndex::IpiTlbShootdown, tlb_shootdown_handler),
+    ];
+    for (index, handler) in handlers {
+        let _ = handler::register(Irq::Vector(index as u8), handler).unwrap();
+    }
+
+    paging::set_shootdown_hook(tlb_shootdown);
+}
+
+
+/// Sends a fixed interrupt with `vector`.
+pub fn send(target: Target, vector: u8) {
+    send_with_mode(target, vector, DeliveryMode::Fixed);
+}
+
+fn send_with_mode(target: Target, vector: u8, mode: DeliveryMode) {
+    without_interrupts(|| unsafe {
+        let apic = &mut *core::ptr::addr_of_mut!(LOCAL_APIC);
+        let icr = match target {
+            Target::Cpu(apic_id) => apic.icr(vector, apic_id, DestinationShorthand::NoShorthand, mode),
+            Target::AllButSelf => apic.icr(vector, 0, DestinationShorthand::AllExcludingSelf, mode),
+            Target::All => apic.icr(vector, 0, DestinationShorthand::AllIncludingSelf, mode),
+        };
+        apic.send_ipi(icr);
+    });
+}
+
+/// Makes the CPU with `index` look at its run queue again, e.g. to leave `hlt`.
+pub fn reschedule(index: usize) -> Result<(), IpiError> {
+    let cpu = cpu::cpus().get(index).ok_or(IpiError::NoSuchCpu)?;
+    send(Target::Cpu(cpu.apic_id), InterruptIndex::IpiReschedule as u8);
+    Ok(())
+}
+
+/// Runs `f` on the CPU with `index` in interrupt context, without waiting for it.
+///
+/// On the calling CPU `f` runs right away.
+pub fn call_on(index: usize, f: impl FnOnce() + Send + 'static) -> Result<(), IpiError> {
+    if index == cpu::current_index() {
+        without_interrupts(f);
+        return Ok(());
+    }
+
+    let cpu = cpu::cpus().get(index).filter(|cpu| cpu.is_online()).ok_or(IpiError::NoSuchCpu)?;
+    let block = percpu::get(index).ok_or(IpiError::NoSuchCpu)?;
+    block.call_queue.push(Box::new(f)).map_err(|_| IpiError::QueueFull)?;
+    send(Target::Cpu(cpu.apic_id), InterruptIndex::IpiCall as u8);
+    Ok(())
+}
+
+
+fn reschedule_handler(_frame: &mut TrapFrame) {
+    // the interrupt itself is enough to leave `hlt`, a thread made ready by another cpu
+    // needs a time slice though
+    crate::thread::rearm_timer();
+}
+
+fn call_handler(_frame: &mut TrapFrame) {
+    let block = percpu::current();
+    while let Some(f) = block.call_queue.pop() {
+        f();
+    }
+}
+
+
+/// The shootdown in flight, one at a time.
+struct Shootdown {
+    start: AtomicU64,
+    pages: AtomicU64,
+    // a bit per cpu index that still has to flush
+    pending: AtomicU64,
+}
+
+static SHOOTDOWN: Shootdown = Shootdown {
+    start: AtomicU64::new(0),
+    pages: AtomicU64::new(0),
+    pending: AtomicU64::new(0),
+};
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+
+
+/// Invalidates `pages` pages from `start` on every other online CPU and waits for them.
+fn tlb_shootdown(start: VirtAddr, pages: u64) {
+    if cpu::online_cpus() <= 1 {
+        return;
+    }
+    let current = cpu::current_index();
+    let others = cpu::cpus().iter()
+        .filter(|cpu| cpu.index != current && cpu.is_online())
+        .fold(0u64, |mask, cpu| mask | 1 << cpu.index);
+    if others == 0 {
+        return;
+    }
+
+    // serve the shootdowns of other cpus while waiting, interrupts may be off here
+    while SHOOTDOWN_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        serve_shootdown();
+        core::hint::spin_loop();
+    }
+
+    SHOOTDOWN.start.store(start.as_u64(), Ordering::Relaxed);
+    SHOOTDOWN.pages.store(pages, Ordering::Relaxed);
+    SHOOTDOWN.pending.store(others, Ordering::Release);
+    send(Target::AllButSelf, InterruptIndex::IpiTlbShootdown as u8);
+
+    while SHOOTDOWN.pending.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+fn serve_shootdown() {
+    let bit = 1 << cpu::current_index();
+    if SHOOTDOWN.pending.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+
+    let start = SHOOTDOWN.start.load(Ordering::Relaxed);
+    let pages = SHOOTDOWN.pages.load(Ordering::Relaxed);
+    match pages > MAX_INVLPG_PAGES {
+        true => tlb::flush_all(),
+        false => {
+            for page in 0..pages {
+                tlb::flush(VirtAddr::new(start + page * 4096));
+            }
+        }
+    }
+    SHOOTDOWN.pending.fetch_and(!bit, Ordering::AcqRel);
+}
+
+fn tlb_shootdown_handler(_frame: &mut TrapFrame) {
+    serve_shootdown();
+}
+
+
+static HALTED: AtomicUsize = AtomicUsize::new(0);
+// tells the NMI handler the NMI came from `halt_others`
+static HALT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops every other CPU, used by the panic handler so only one CPU prints.
+///
+/// The halt is sent as an NMI so it also stops CPUs spinning with interrupts disabled.
+pub fn halt_others() {
+    let others = cpu::online_cpus().saturating_sub(1);
+    if others == 0 {
+        return;
+    }
+
+    HALT_REQUESTED.store(true, Ordering::Release);
+    send_with_mode(Target::AllButSelf, 0, DeliveryMode::NMI);
+    for _ in 0..HALT_WAIT_SPINS {
+        if HALTED.load(Ordering::Acquire) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Whether an NMI is the one `halt_others` sent.
+pub(super) fn halt_requested() -> bool {
+    HALT_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Called from the NMI handler, further NMIs stay blocked since it never returns.
+pub(super) fn halt_this_cpu() -> ! {
+    x86_64::instructions::interrupts::disable();
+    HALTED.fetch_add(1, Ordering::Release);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -1,5 +1,10 @@
 pub mod apic;
+pub mod exception;
+pub mod handler;
 pub mod ioapic;
 pub mod idt;
+pub mod ipi;
+pub mod routing;
 
 pub use idt::InterruptIndex;
+pub use handler::{register, unregister, Irq};
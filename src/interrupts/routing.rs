@@ -0,0 +1,203 @@
+use super::ioapic::{DeliveryMode, IoApic, IrqEntry};
+use crate::acpi::acpi::madt::{Madt, MadtEntry};
+use crate::memory;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+
+static IRQ_ROUTER: Mutex<Option<IrqRouter>> = Mutex::new(None);
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Where an ISA IRQ ends up after applying the MADT interrupt source overrides.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+impl IsaRoute {
+    /// ISA interrupts are active high and edge triggered unless overridden.
+    const fn identity(irq: u8) -> Self {
+        Self {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }
+    }
+
+    /// Decodes the MPS INTI flags of an override, "conforms to bus" keeps the ISA default.
+    fn from_override(gsi: u32, flags: u16) -> Self {
+        let polarity = match flags & 0b11 {
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        };
+        let trigger_mode = match (flags >> 2) & 0b11 {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        };
+
+        Self {
+            gsi,
+            polarity,
+            trigger_mode,
+        }
+    }
+}
+
+
+struct IoApicRange {
+    ioapic: IoApic,
+    gsi_base: u32,
+    pins: u32,
+}
+
+pub struct IrqRouter {
+    ioapics: Vec<IoApicRange>,
+    isa_routes: [IsaRoute; 16],
+    bsp_apic_id: u8,
+}
+
+impl IrqRouter {
+    fn ioapic_for_gsi(&mut self, gsi: u32) -> Option<(&mut IoApic, u8)> {
+        self.ioapics
+            .iter_mut()
+            .find(|r| gsi >= r.gsi_base && gsi < r.gsi_base + r.pins)
+            .map(|r| (&mut r.ioapic, (gsi - r.gsi_base) as u8))
+    }
+
+    fn write_gsi(&mut self, gsi: u32, entry: IrqEntry) {
+        let (ioapic, pin) = self.ioapic_for_gsi(gsi)
+            .unwrap_or_else(|| panic!("no io apic handles GSI {}", gsi));
+        ioapic.write_irq(pin, entry);
+    }
+
+    fn update_gsi(&mut self, gsi: u32, f: impl FnOnce(&mut IrqEntry)) {
+        let (ioapic, pin) = self.ioapic_for_gsi(gsi)
+            .unwrap_or_else(|| panic!("no io apic handles GSI {}", gsi));
+        let mut entry = ioapic.read_irq(pin);
+        f(&mut entry);
+        ioapic.write_irq(pin, entry);
+    }
+}
+
+
+/// Finds every io apic and interrupt source override in the MADT and masks all pins.
+pub fn init(madt: &Madt, bsp_apic_id: u8) {
+    let mut router = IrqRouter {
+        ioapics: Vec::new(),
+        isa_routes: core::array::from_fn(|irq| IsaRoute::identity(irq as u8)),
+        bsp_apic_id,
+    };
+    let mut nmi_sources = Vec::new();
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic(entry) => {
+                let addr = memory::map_mmio(PhysAddr::new(entry.io_apic_address as u64), 0x20);
+                let mut ioapic = unsafe { IoApic::new(addr.as_u64() as usize) };
+                let (_, pins) = ioapic.read_ver_and_max_entry();
+
+                for pin in 0..pins as u8 {
+                    let mut irq_entry = IrqEntry::MASK;
+                    irq_entry.set_vector(0xFF);
+                    ioapic.write_irq(pin, irq_entry);
+                }
+
+                router.ioapics.push(IoApicRange {
+                    ioapic,
+                    gsi_base: entry.global_system_interrupt_base,
+                    pins: pins as u32,
+                });
+            }
+            MadtEntry::InterruptSourceOverride(entry) if entry.bus == 0 && entry.irq < 16 => {
+                let (irq, gsi, flags) = (entry.irq, entry.global_system_interrupt, entry.flags);
+                router.isa_routes[irq as usize] = IsaRoute::from_override(gsi, flags);
+            }
+            MadtEntry::NmiSource(entry) => {
+                nmi_sources.push((entry.global_system_interrupt, entry.flags));
+            }
+            _ => {}
+        }
+    }
+
+    for (gsi, flags) in nmi_sources {
+        let route = IsaRoute::from_override(gsi, flags);
+        let mut irq_entry = build_entry(0, route.polarity, route.trigger_mode, bsp_apic_id);
+        irq_entry.set_delivery_mode(DeliveryMode::NMI);
+        router.write_gsi(gsi, irq_entry);
+    }
+
+    for range in router.ioapics.iter() {
+        log::info!(
+            "io apic: GSI {}..{}",
+            range.gsi_base,
+            range.gsi_base + range.pins,
+        );
+    }
+
+    *IRQ_ROUTER.lock() = Some(router);
+}
+
+
+fn build_entry(vector: u8, polarity: Polarity, trigger_mode: TriggerMode, apic_id: u8) -> IrqEntry {
+    let mut entry = IrqEntry::empty();
+    entry.set_vector(vector);
+    entry.set_delivery_mode(DeliveryMode::Fixed);
+    entry.set_destination(apic_id);
+    if polarity == Polarity::ActiveLow {
+        entry.insert(IrqEntry::PIN_POLARITY);
+    }
+    if trigger_mode == TriggerMode::Level {
+        entry.insert(IrqEntry::TRIGGER_MODE);
+    }
+    entry
+}
+
+fn with_router<R>(f: impl FnOnce(&mut IrqRouter) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        f(IRQ_ROUTER.lock().as_mut().expect("irq routing not initialized"))
+    })
+}
+
+
+/// Resolves an ISA IRQ to its GSI, polarity and trigger mode.
+pub fn isa_route(irq: u8) -> IsaRoute {
+    with_router(|router| router.isa_routes[irq as usize])
+}
+
+/// Delivers `gsi` as `vector` to the BSP and unmasks it.
+pub fn route_gsi(gsi: u32, vector: u8, polarity: Polarity, trigger_mode: TriggerMode) {
+    with_router(|router| {
+        let entry = build_entry(vector, polarity, trigger_mode, router.bsp_apic_id);
+        router.write_gsi(gsi, entry);
+    });
+}
+
+/// Delivers the ISA `irq` as `vector` to the BSP and unmasks it, returns the GSI it was routed through.
+pub fn route_isa(irq: u8, vector: u8) -> u32 {
+    let route = isa_route(irq);
+    route_gsi(route.gsi, vector, route.polarity, route.trigger_mode);
+    route.gsi
+}
+
+pub fn mask_gsi(gsi: u32) {
+    with_router(|router| router.update_gsi(gsi, |entry| entry.insert(IrqEntry::MASK)));
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    with_router(|router| router.update_gsi(gsi, |entry| entry.remove(IrqEntry::MASK)));
+}
@@ -0,0 +1,492 @@
+//! Kernel logger behind the `log` crate macros.
+//!
+//! Records go to the serial console and to an in-memory ring buffer that can be read back
+//! with `dmesg`. Levels are filtered per module with the `log=` option of the command line,
+//! e.g. `log=warn,kernel::task=debug`. The longest matching module prefix wins.
+//!
+//! Once the per-CPU blocks exist logging never takes a lock, so it is safe from interrupt
+//! handlers and NMIs. A record is formatted into a lock-free ring of the logging CPU and
+//! written out later by the `drain` task, or right away when logged from thread context
+//! before that task runs.
+
+use crate::{com, cpu::percpu, print, time};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::future::poll_fn;
+use core::str::FromStr;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+const DMESG_SIZE: usize = 64 * 1024;
+// bytes per ring slot, longer records take consecutive slots
+const RECORD_SIZE: usize = 256;
+pub(crate) const RING_SLOTS: usize = 64;
+// records that don't fit this many slots are cut off
+const MAX_RECORD_SLOTS: usize = 16;
+const TRUNCATED: &str = " [truncated]\n";
+// records logged from interrupt handlers don't wake the drain task
+const DRAIN_PERIOD: Duration = Duration::from_millis(20);
+
+// slot states
+const EMPTY: u8 = 0;
+const FULL: u8 = 1;
+
+static LOGGER: KernelLogger = KernelLogger;
+// null until `set_filters`, then every module logs at `DEFAULT_LEVEL`
+static FILTERS: AtomicPtr<Filters> = AtomicPtr::new(null_mut());
+static DMESG: Mutex<RingBuffer<DMESG_SIZE>> = Mutex::new(RingBuffer::new());
+static DRAIN_WAKER: AtomicWaker = AtomicWaker::new();
+// set once the drain task runs, until then thread context flushes by itself
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+// only one CPU empties the rings at a time
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+
+pub(crate) struct Filters {
+    default: LevelFilter,
+    // (module path prefix, level)
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    /// Parses a `log=` spec, also returns the entries that aren't valid.
+    pub(crate) fn parse(spec: &str) -> (Self, Vec<&str>) {
+        let mut filters = Filters {
+            default: DEFAULT_LEVEL,
+            modules: Vec::new(),
+        };
+        let mut invalid = Vec::new();
+
+        for entry in spec.split(',').filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                None => match LevelFilter::from_str(entry) {
+                    Ok(level) => filters.default = level,
+                    Err(_) => invalid.push(entry),
+                },
+                Some((module, level)) => match LevelFilter::from_str(level) {
+                    Ok(level) => filters.modules.push((String::from(module), level)),
+                    Err(_) => invalid.push(entry),
+                },
+            }
+        }
+        (filters, invalid)
+    }
+
+    pub(crate) fn level(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .filter(|(module, _)| is_module_prefix(module, target))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+/// `kernel::task` matches `kernel::task` and `kernel::task::executor` but not `kernel::tasks`.
+pub(crate) fn is_module_prefix(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+
+/// The last `SIZE` bytes of log lines, the oldest ones are overwritten.
+pub(crate) struct RingBuffer<const SIZE: usize> {
+    buffer: [u8; SIZE],
+    // next byte to write
+    head: usize,
+    wrapped: bool,
+    // the oldest byte left is in the middle of a line
+    partial: bool,
+}
+
+impl<const SIZE: usize> RingBuffer<SIZE> {
+    pub(crate) const fn new() -> Self {
+        RingBuffer {
+            buffer: [0; SIZE],
+            head: 0,
+            wrapped: false,
+            partial: false,
+        }
+    }
+
+    /// Oldest first, without the start of a line that was partly overwritten.
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        let mut bytes = self.buffer[..self.head].to_vec();
+        if self.wrapped {
+            bytes.splice(0..0, self.buffer[self.head..].iter().copied());
+        }
+        if !self.partial {
+            return bytes;
+        }
+        match bytes.iter().position(|&byte| byte == b'\n') {
+            Some(end) => bytes.split_off(end + 1),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<const SIZE: usize> Write for RingBuffer<SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.wrapped {
+                // the next oldest byte starts a line if the one lost ended one
+                self.partial = self.buffer[self.head] != b'\n';
+            }
+            self.buffer[self.head] = byte;
+            self.head += 1;
+            if self.head == SIZE {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Formatted records of one CPU, written by that CPU and emptied by whoever drains.
+///
+/// Only the owning CPU pushes, with interrupts disabled, so the only concurrent pushes come
+/// from NMIs and exceptions, which finish before the code they interrupted continues.
+pub(crate) struct LogRing {
+    slots: Box<[Slot]>,
+    // next slot to fill
+    head: AtomicUsize,
+    // next slot to drain
+    tail: AtomicUsize,
+    // records lost because the ring was full
+    dropped: AtomicUsize,
+}
+
+struct Slot {
+    state: AtomicU8,
+    len: UnsafeCell<usize>,
+    bytes: UnsafeCell<[u8; RECORD_SIZE]>,
+}
+
+// the slot contents are only accessed by the side the state hands them to
+unsafe impl Sync for LogRing {}
+
+impl LogRing {
+    pub(crate) fn new() -> Self {
+        LogRing {
+            slots: (0..RING_SLOTS).map(|_| Slot {
+                state: AtomicU8::new(EMPTY),
+                len: UnsafeCell::new(0),
+                bytes: UnsafeCell::new([0; RECORD_SIZE]),
+            }).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Formats `record` with a newline into the next free slots, counts it as dropped
+    /// when they are taken.
+    pub(crate) fn push(&self, record: &impl fmt::Display) {
+        // measured first, the slots have to be reserved before the record is written
+        let mut measure = Chunks::new(MAX_RECORD_SLOTS, |_, _, _| {});
+        let _ = writeln!(measure, "{}", record);
+        let count = measure.count();
+
+        without_interrupts(|| {
+            // an NMI can push in between, then the exchange fails and the next slots are tried
+            let mut head = self.head.load(Ordering::Relaxed);
+            loop {
+                let free = (head..head + count)
+                    .all(|index| self.slots[index % RING_SLOTS].state.load(Ordering::Acquire) == EMPTY);
+                if !free {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                match self.head.compare_exchange(head, head + count, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(current) => head = current,
+                }
+            }
+
+            let slot = |chunk: usize| &self.slots[(head + chunk) % RING_SLOTS];
+            for chunk in 0..count {
+                unsafe { *slot(chunk).len.get() = 0 };
+            }
+            let mut chunks = Chunks::new(count, |chunk, offset, bytes: &[u8]| unsafe {
+                let slot = slot(chunk);
+                (&mut *slot.bytes.get())[offset..offset + bytes.len()].copy_from_slice(bytes);
+                *slot.len.get() = offset + bytes.len();
+            });
+            let _ = writeln!(chunks, "{}", record);
+            if measure.truncated || chunks.truncated {
+                unsafe { mark_truncated(slot(count - 1)) };
+            }
+
+            // the first chunk last, the drainer only starts on a record that is complete
+            for chunk in (0..count).rev() {
+                slot(chunk).state.store(FULL, Ordering::Release);
+            }
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.slots[tail % RING_SLOTS].state.load(Ordering::Acquire) != FULL
+    }
+
+    /// Passes every record in order to `f`, the caller has to be the only one draining.
+    pub(crate) fn drain(&self, cpu: usize, mut f: impl FnMut(&str)) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            let mut note = String::new();
+            let _ = writeln!(note, "[{} log records dropped on cpu{}]", dropped, cpu);
+            f(&note);
+        }
+
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail % RING_SLOTS];
+            if slot.state.load(Ordering::Acquire) != FULL {
+                break;
+            }
+            let bytes = unsafe { &(&*slot.bytes.get())[..*slot.len.get()] };
+            // `Chunks` only cuts at char boundaries
+            f(unsafe { core::str::from_utf8_unchecked(bytes) });
+            slot.state.store(EMPTY, Ordering::Release);
+            tail += 1;
+            self.tail.store(tail, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Ends the text in `slot` with `TRUNCATED`, the slot has to be reserved.
+unsafe fn mark_truncated(slot: &Slot) {
+    let bytes = &mut *slot.bytes.get();
+    let len = &mut *slot.len.get();
+    let mut end = (*len).min(RECORD_SIZE - TRUNCATED.len());
+    // back to a char boundary
+    while end > 0 && end < *len && bytes[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    bytes[end..end + TRUNCATED.len()].copy_from_slice(TRUNCATED.as_bytes());
+    *len = end + TRUNCATED.len();
+}
+
+/// Splits formatted text into chunks of up to `RECORD_SIZE` bytes, only at char boundaries
+/// so every chunk is valid UTF-8. `store` gets the chunk index, the offset in it and the bytes.
+struct Chunks<F: FnMut(usize, usize, &[u8])> {
+    limit: usize,
+    chunk: usize,
+    len: usize,
+    // text didn't fit in `limit` chunks
+    truncated: bool,
+    store: F,
+}
+
+impl<F: FnMut(usize, usize, &[u8])> Chunks<F> {
+    fn new(limit: usize, store: F) -> Self {
+        Chunks {
+            limit,
+            chunk: 0,
+            len: 0,
+            truncated: false,
+            store,
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.chunk + 1
+    }
+}
+
+impl<F: FnMut(usize, usize, &[u8])> Write for Chunks<F> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            let mut end = s.len().min(RECORD_SIZE - self.len);
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                if self.chunk + 1 == self.limit {
+                    self.truncated = true;
+                    return Err(fmt::Error);
+                }
+                self.chunk += 1;
+                self.len = 0;
+                continue;
+            }
+            (self.store)(self.chunk, self.len, &s.as_bytes()[..end]);
+            self.len += end;
+            s = &s[end..];
+        }
+        Ok(())
+    }
+}
+
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let filters = unsafe { FILTERS.load(Ordering::Acquire).as_ref() };
+        let level = filters.map_or(DEFAULT_LEVEL, |filters| filters.level(metadata.target()));
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = Line {
+            nanos: time::now(),
+            cpu: 0,
+            level: record.level(),
+            target: record.target(),
+            args: record.args(),
+        };
+
+        // early boot, there is no heap or per-CPU block yet and only the BSP runs
+        if percpu::get(0).is_none() {
+            without_interrupts(|| {
+                let _ = writeln!(DMESG.lock(), "{}", line);
+            });
+            print!("{}\n", line);
+            return;
+        }
+
+        let percpu = percpu::current();
+        line.cpu = percpu.index;
+        percpu.log_ring.push(&line);
+
+        // interrupt handlers and lock holders run with interrupts disabled, they can't
+        // take the console lock or wake a task
+        if !interrupts::are_enabled() || percpu.interrupt_depth() != 0 {
+            return;
+        }
+        match DEFERRED.load(Ordering::Relaxed) {
+            true => DRAIN_WAKER.wake(),
+            false => self.flush(),
+        }
+    }
+
+    fn flush(&self) {
+        if DRAINING.swap(true, Ordering::Acquire) {
+            return;
+        }
+        // one record per lock, a whole batch would keep interrupts off for too long
+        for_each_ring(|cpu, ring| ring.drain(cpu, |record| {
+            without_interrupts(|| {
+                let _ = DMESG.lock().write_str(record);
+            });
+            print!("{}", record);
+        }));
+        DRAINING.store(false, Ordering::Release);
+    }
+}
+
+fn for_each_ring(mut f: impl FnMut(usize, &LogRing)) {
+    for index in 0..percpu::MAX_CPUS {
+        if let Some(block) = percpu::get(index) {
+            f(index, &block.log_ring);
+        }
+    }
+}
+
+/// One formatted record, `[    1.234567] cpu0 INFO  kernel::time: message`.
+struct Line<'a> {
+    nanos: u64,
+    cpu: usize,
+    level: Level,
+    target: &'a str,
+    args: &'a fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] cpu{} {:5} {}: {}",
+            self.nanos / 1_000_000_000,
+            self.nanos % 1_000_000_000 / 1000,
+            self.cpu,
+            self.level,
+            self.target,
+            self.args,
+        )
+    }
+}
+
+
+/// Installs the logger, records logged before are dropped.
+///
+/// Until `init_filters` runs every module logs at the default level.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(DEFAULT_LEVEL);
+}
+
+/// Applies the `log=` option of the command line, needs the heap.
+pub fn init_filters(cmdline: &str) {
+    if let Some(spec) = cmdline.split_whitespace().find_map(|option| option.strip_prefix("log=")) {
+        set_filters(spec);
+    }
+}
+
+/// Replaces the level filters, `spec` is a comma separated list of `<level>` for the
+/// default and `<module>=<level>` entries.
+pub fn set_filters(spec: &str) {
+    let (filters, invalid) = Filters::parse(spec);
+    let max_level = filters.max_level();
+    // the old filters are leaked, another cpu or an NMI may still be reading them
+    FILTERS.swap(Box::into_raw(Box::new(filters)), Ordering::AcqRel);
+    log::set_max_level(max_level);
+
+    for entry in invalid {
+        log::warn!("ignoring invalid log filter {:?}", entry);
+    }
+}
+
+/// Writes out the per-CPU rings whenever something is logged, runs as a background task.
+pub async fn drain() {
+    DEFERRED.store(true, Ordering::Relaxed);
+    loop {
+        log::logger().flush();
+
+        let pending = poll_fn(|cx| {
+            DRAIN_WAKER.register(cx.waker());
+            let mut empty = true;
+            for_each_ring(|_, ring| empty &= ring.is_empty());
+            match empty {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            }
+        });
+        let _ = crate::task::time::timeout(DRAIN_PERIOD, pending).await;
+    }
+}
+
+/// Switches the console to panic mode and writes out every CPU's ring, see `begin_panic`.
+///
+/// The other CPUs have to be halted, a drain they were in the middle of is taken over.
+pub fn enter_panic_mode() {
+    com::enter_panic_mode();
+    for_each_ring(|cpu, ring| ring.drain(cpu, |record| print!("{}", record)));
+}
+
+/// Everything still in the log ring buffer, oldest line first.
+pub fn dmesg() -> String {
+    log::logger().flush();
+    let bytes = without_interrupts(|| DMESG.lock().contents());
+    String::from_utf8_lossy(&bytes).into_owned()
+}
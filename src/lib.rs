@@ -1,7 +1,7 @@
 #![no_std]
 #![feature(abi_x86_interrupt, const_mut_refs)]
 
-use core::{arch::asm, panic::PanicInfo};
+use core::{arch::asm, panic::PanicInfo, sync::atomic::{AtomicUsize, Ordering}};
 use alloc::vec;
 use lazy_static::lazy_static;
 use multiboot2::{BootInformation, BootInformationHeader};
@@ -11,15 +11,21 @@ extern crate alloc;
 
 mod acpi;
 mod com;
+mod cpu;
+mod gdt;
 mod memory;
 mod pci;
+mod symbols;
 mod interrupts;
+mod klog;
 mod task;
 mod tests;
+mod thread;
+mod time;
 
 use acpi::acpi::madt::MadtEntry;
-use task::{executor::Executor, keyboard, Task};
-use interrupts::apic::LOCAL_APIC;
+use task::{executor::Executor, keyboard, serial, Priority, Task};
+use interrupts::{apic::LOCAL_APIC, Irq};
 
 
 lazy_static! {
@@ -27,8 +33,38 @@ lazy_static! {
 }
 
 
+const NO_CPU: usize = usize::MAX;
+// index of the cpu reporting a panic
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+/// Halts the other CPUs and switches the console and log to panic mode.
+///
+/// Fatal exceptions call this before their crash report. If another CPU is already
+/// panicking the calling one halts instead.
+pub(crate) fn begin_panic() {
+    x86_64::instructions::interrupts::disable();
+    let this = match cpu::percpu::get(0) {
+        Some(_) => cpu::current_index(),
+        // before the per-cpu blocks only the bsp runs
+        None => 0,
+    };
+    match PANIC_CPU.compare_exchange(NO_CPU, this, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {}
+        // a fatal exception already began it
+        Err(cpu) if cpu == this => return,
+        // the other cpu will halt this one
+        Err(_) => loop {
+            unsafe { asm!("hlt") };
+        },
+    }
+    interrupts::ipi::halt_others();
+    klog::enter_panic_mode();
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    begin_panic();
+
     println!("PANIC!");
     println!("{}", info);
     loop {
@@ -39,17 +75,27 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
+    // nothing can be printed if this fails
+    let _ = com::init();
+    klog::init();
+    gdt::init();
     interrupts::idt::init();
-    tests::run_tests();
     
     let mbi = unsafe {
         BootInformation::load(mbi_ptr as *const BootInformationHeader).unwrap()
     };
-    
+    symbols::init(&mbi);
+
     // init allocator
     unsafe { memory::init(&mbi) };
-    println!("|||||| Multiboot memmory map:");
-    println!("{:#?}", mbi.memory_map_tag().unwrap());
+    cpu::percpu::init(0);
+    // some of the tests need the heap
+    tests::run_tests();
+
+    let cmdline = mbi.command_line_tag().and_then(|tag| tag.cmdline().ok()).unwrap_or("");
+    klog::init_filters(cmdline);
+    com::init_ports(cmdline);
+    log::debug!("multiboot memory map: {:#?}", mbi.memory_map_tag().unwrap());
 
     // parse acpi
     let acpi_tables = acpi::read(&mbi);
@@ -61,7 +107,7 @@ pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
     let mut madt_ioapic = None;
     let mut madt_lapic_override = None;
     for entry in madt.entries() {
-        println!("{:#?}", entry);
+        log::debug!("{:#?}", entry);
         match entry {
             MadtEntry::LocalApic(val) => madt_lapic = Some(val),
             MadtEntry::IoApic(val) => madt_ioapic = Some(val),
@@ -72,8 +118,7 @@ pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
     let madt_ioapic = madt_ioapic.unwrap();
 
 
-    println!("|||||| io apic madt entry:");
-    println!("{:#?}", madt_ioapic);
+    log::debug!("io apic madt entry: {:#?}", madt_ioapic);
 
     // check for address override
     let lapic_addr = if let Some(lapic_override) = madt_lapic_override {
@@ -84,14 +129,26 @@ pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
 
     // init lapic
     unsafe {
-        LOCAL_APIC.init( lapic_addr as *mut u32 );
-        LOCAL_APIC.enable_timer();
+        LOCAL_APIC.init(x86_64::PhysAddr::new(lapic_addr));
     }
+    log::info!("local apic in {} mode", if unsafe { LOCAL_APIC.is_x2apic() } { "x2apic" } else { "xapic" });
+    time::hpet::init(&acpi_tables);
+    time::init();
     
-    // init iopic
-    let mut ioapic = unsafe {
-        interrupts::ioapic::IoApic::new(madt_ioapic.io_apic_address as usize)
-    };
+    // route legacy irqs through the io apics
+    // the io apic destination field only holds 8 bit IDs
+    let bsp_apic_id = unsafe { LOCAL_APIC.apic_id() };
+    let bsp_apic_id = u8::try_from(bsp_apic_id).expect("BSP APIC ID doesn't fit an io apic destination");
+    interrupts::routing::init(&madt, bsp_apic_id);
+    let _ = interrupts::register(Irq::Isa(1), interrupts::idt::keyboard_interrupt_handler).unwrap();
+    if let Some((index, uart)) = com::shell_port() {
+        let _ = interrupts::register(Irq::Isa(com::isa_irq(index)), interrupts::idt::serial_interrupt_handler).unwrap();
+        uart.enable_rx_interrupt();
+    }
+
+    // start the application processors
+    interrupts::ipi::init();
+    cpu::smp::init(&madt);
 
     // println!("{:#?}", fadt.dsdt_address()); // TODO
 
@@ -99,7 +156,8 @@ pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
     let framebuffer_tag = mbi.framebuffer_tag().unwrap().unwrap();
     let address = framebuffer_tag.address();
     let framebuffer_size = framebuffer_tag.pitch() as usize * framebuffer_tag.height() as usize;
-    let framebuffer_ptr = address as *mut u8;
+    let framebuffer_ptr = memory::map_mmio(x86_64::PhysAddr::new(address), framebuffer_size as u64)
+        .as_mut_ptr::<u8>();
 
 
     { // PCIE
@@ -149,10 +207,17 @@ pub extern "C" fn kmain(mbi_ptr: u32) -> ! {
         // }
     }
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    let executor = Executor::new();
+    executor.spawn(Task::with_priority(klog::drain(), Priority::Background));
+    executor.spawn(Task::with_priority(keyboard::print_keypresses(), Priority::Interactive));
+    if com::shell_port().is_some() {
+        executor.spawn(Task::with_priority(serial::shell(), Priority::Interactive));
+    }
     executor.spawn(Task::new(example_task()));
-    executor.run();
+
+    thread::init();
+    thread::spawn(move || executor.run());
+    thread::idle();
 
 
     println!("Success");
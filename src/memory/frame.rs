@@ -0,0 +1,280 @@
+use crate::acpi;
+use multiboot2::{BootInformation, MemoryAreaType};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+
+pub const FRAME_SIZE: u64 = 4096;
+
+// everything below 1 MiB is left alone (BIOS data, EBDA, AP trampoline)
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+
+pub static FRAME_ALLOCATOR: Mutex<BitmapFrameAllocator> = Mutex::new(BitmapFrameAllocator::empty());
+
+
+/// Physical frame allocator with one bit per 4 KiB frame, a set bit means the frame is in use.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    free_frames: usize,
+    // index of the lowest frame that could be free
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    const fn empty() -> Self {
+        Self {
+            bitmap: &mut [],
+            frame_count: 0,
+            free_frames: 0,
+            next_free: 0,
+        }
+    }
+
+    /// Allocator for `frame_count` frames whose state is already in `bitmap`.
+    pub(crate) fn with_bitmap(bitmap: &'static mut [u64], frame_count: usize) -> Self {
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            free_frames: 0,
+            next_free: 0,
+        };
+        allocator.free_frames = allocator.count_free_frames();
+        allocator
+    }
+
+    /// Builds the bitmap from the multiboot2 memory map.
+    ///
+    /// The bitmap itself is placed in the first available area that is large enough
+    /// and doesn't overlap anything the kernel still needs.
+    unsafe fn init(&mut self, mbi: &BootInformation) {
+        let memory_areas = mbi
+            .memory_map_tag()
+            .unwrap()
+            .memory_areas();
+
+        let memory_end = memory_areas.iter()
+            .filter(|a| a.typ() == MemoryAreaType::Available)
+            .map(|a| a.end_address())
+            .max()
+            .unwrap();
+
+        let frame_count = (memory_end / FRAME_SIZE) as usize;
+        let bitmap_len = (frame_count + 63) / 64;
+        let bitmap_size = (bitmap_len * 8) as u64;
+
+        // find a place for the bitmap
+        let bitmap_addr = memory_areas.iter()
+            .filter(|a| a.typ() == MemoryAreaType::Available)
+            .find_map(|a| {
+                let mut start = align_up(a.start_address().max(LOW_MEMORY_END), FRAME_SIZE);
+                loop {
+                    let end = start + bitmap_size;
+                    if end > a.end_address() {
+                        return None;
+                    }
+
+                    let mut overlap = None;
+                    for_each_reserved_region(mbi, |r_start, r_end| {
+                        if r_start < end && r_end > start {
+                            overlap = Some(overlap.unwrap_or(0).max(r_end));
+                        }
+                    });
+
+                    match overlap {
+                        Some(r_end) => start = align_up(r_end, FRAME_SIZE),
+                        None => return Some(start),
+                    }
+                }
+            })
+            .expect("no memory area large enough for the frame bitmap");
+
+        self.bitmap = core::slice::from_raw_parts_mut(bitmap_addr as *mut u64, bitmap_len);
+        self.frame_count = frame_count;
+
+        // everything is used until proven otherwise
+        self.bitmap.fill(!0);
+        for area in memory_areas.iter().filter(|a| a.typ() == MemoryAreaType::Available) {
+            self.set_range(area.start_address(), area.end_address(), false);
+        }
+
+        for_each_reserved_region(mbi, |start, end| self.set_range(start, end, true));
+        self.set_range(bitmap_addr, bitmap_addr + bitmap_size, true);
+
+        self.free_frames = self.count_free_frames();
+        self.next_free = 0;
+    }
+
+    fn count_free_frames(&self) -> usize {
+        (0..self.frame_count).filter(|&i| !self.is_used(i)).count()
+    }
+
+    /// Moves the bitmap pointer by `offset` after the page tables were switched.
+    pub(super) unsafe fn relocate(&mut self, offset: u64) {
+        let bitmap_addr = self.bitmap.as_mut_ptr() as u64 + offset;
+        self.bitmap = core::slice::from_raw_parts_mut(bitmap_addr as *mut u64, self.bitmap.len());
+    }
+
+    /// Marks every frame touching `start..end` as used or frees every frame fully inside it.
+    fn set_range(&mut self, start: u64, end: u64, used: bool) {
+        let (first, last) = match used {
+            true => (start / FRAME_SIZE, align_up(end, FRAME_SIZE) / FRAME_SIZE),
+            false => (align_up(start, FRAME_SIZE) / FRAME_SIZE, end / FRAME_SIZE),
+        };
+
+        for index in first as usize..(last as usize).min(self.frame_count) {
+            self.set_used(index, used);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        match used {
+            true => self.bitmap[index / 64] |= 1 << (index % 64),
+            false => self.bitmap[index / 64] &= !(1 << (index % 64)),
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn alloc(&mut self) -> Option<PhysFrame> {
+        let start_word = self.next_free / 64;
+        let word_index = (start_word..self.bitmap.len()).find(|&i| self.bitmap[i] != !0)?;
+        let index = word_index * 64 + (!self.bitmap[word_index]).trailing_zeros() as usize;
+        if index >= self.frame_count {
+            return None;
+        }
+
+        self.set_used(index, true);
+        self.free_frames -= 1;
+        self.next_free = index + 1;
+        Some(frame_from_index(index))
+    }
+
+    pub fn free(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(self.is_used(index), "double free of frame {:?}", frame);
+
+        self.set_used(index, false);
+        self.free_frames += 1;
+        self.next_free = self.next_free.min(index);
+    }
+
+    /// Allocates `count` physically contiguous frames, the first one aligned to `align` bytes.
+    ///
+    /// Returns `None` for a `count` of 0, there is no frame to hand out.
+    pub fn alloc_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two() && align >= FRAME_SIZE, "invalid alignment {:#X}", align);
+        if count == 0 {
+            return None;
+        }
+        let step = (align / FRAME_SIZE) as usize;
+
+        let mut start = align_up(self.next_free as u64, step as u64) as usize;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&i| self.is_used(i)) {
+                // skip past the used frame
+                Some(used) => start = align_up(used as u64 + 1, step as u64) as usize,
+                None => {
+                    for index in start..start + count {
+                        self.set_used(index, true);
+                    }
+                    self.free_frames -= count;
+                    return Some(frame_from_index(start));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn free_contiguous(&mut self, first: PhysFrame, count: usize) {
+        let start = (first.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in start..start + count {
+            self.free(frame_from_index(index));
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.alloc()
+    }
+}
+
+
+/// Calls `f` with every physical range that must never be handed out:
+/// low memory, the kernel image and its symbols, the mbi, the boot modules and the ACPI tables.
+fn for_each_reserved_region(mbi: &BootInformation, mut f: impl FnMut(u64, u64)) {
+    f(0, LOW_MEMORY_END);
+
+    let kernel_sections = mbi.elf_sections().unwrap().filter(|s| s.is_allocated());
+    let (kernel_start, kernel_end) = kernel_sections.fold((u64::MAX, 0), |(start, end), s| {
+        (start.min(s.start_address()), end.max(s.end_address()))
+    });
+    f(kernel_start, kernel_end);
+
+    // GRUB also loads the symbol and string tables, the crash reports read them
+    for section in mbi.elf_sections().unwrap().filter(|s| !s.is_allocated() && s.start_address() != 0) {
+        f(section.start_address(), section.end_address());
+    }
+
+    f(mbi.start_address() as u64, mbi.end_address() as u64);
+
+    for module in mbi.module_tags() {
+        f(module.start_address() as u64, module.end_address() as u64);
+    }
+
+    acpi::for_each_table_region(mbi, |start, len| f(start as u64, (start + len) as u64));
+}
+
+fn frame_from_index(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+const fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+
+/// Has to be called before any other function in this module.
+pub unsafe fn init(mbi: &BootInformation) {
+    FRAME_ALLOCATOR.lock().init(mbi);
+}
+
+pub fn alloc_frame() -> Option<PhysFrame> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().alloc()
+    })
+}
+
+pub fn free_frame(frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().free(frame)
+    })
+}
+
+pub fn alloc_contiguous(count: usize, align: u64) -> Option<PhysFrame> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().alloc_contiguous(count, align)
+    })
+}
+
+pub fn free_contiguous(first: PhysFrame, count: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().free_contiguous(first, count)
+    })
+}
@@ -0,0 +1,94 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+
+use crate::println;
+use multiboot2::BootInformation;
+use linked_list_allocator::LockedHeap;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+pub mod frame;
+pub mod paging;
+pub mod stack;
+pub mod window;
+
+pub use frame::{alloc_contiguous, alloc_frame, free_contiguous, free_frame, FRAME_SIZE};
+pub use paging::{map_mmio, phys_to_virt};
+
+
+const HEAP_START: usize = 0xFFFF_C000_0000_0000;
+const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+
+#[global_allocator]
+pub static ALLOCATOR: IrqSafeHeap = IrqSafeHeap(LockedHeap::empty());
+
+
+/// Heap that can be used from interrupt handlers, e.g. when a waker is dropped there.
+///
+/// Interrupts stay disabled while the heap lock is held, so a handler can't deadlock on it.
+pub struct IrqSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for IrqSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
+
+impl Deref for IrqSafeHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.0
+    }
+}
+
+#[no_mangle]
+pub unsafe fn init(mbi: &BootInformation) {
+    frame::init(mbi);
+
+    {
+        let frame_allocator = frame::FRAME_ALLOCATOR.lock();
+        log::info!(
+            "frame allocator: {} of {} frames free",
+            frame_allocator.free_frames(),
+            frame_allocator.total_frames(),
+        );
+    }
+
+    paging::init(mbi);
+
+    // the heap doesn't have to be physically contiguous
+    let heap_flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for offset in (0..HEAP_SIZE).step_by(FRAME_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((HEAP_START + offset) as u64));
+        let frame = alloc_frame().expect("not enough memory for the heap");
+        paging::map(page, frame, heap_flags).unwrap();
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(
+            HEAP_START as *mut u8,
+            HEAP_SIZE,
+        );
+    };
+
+
+    // TEMP checks if allocator implementation is valid
+    // remove for release
+    unsafe {
+        let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+        let ptr = ALLOCATOR.alloc(layout);
+        match ptr as u64 {
+            0 => println!("allocator test: FAILED"),
+            _ => println!("allocator test: SUCCESS"),
+        }
+        ALLOCATOR.dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,249 @@
+use super::frame::{self, FRAME_SIZE};
+use crate::CPUID;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use multiboot2::{BootInformation, ElfSectionFlags, MemoryAreaType};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::{
+        control::{Cr3, Cr3Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MapToError, UnmapError, FlagUpdateError, Translate},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+
+/// All of physical memory is mapped starting at this address.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// Device registers mapped by `map_mmio` live here.
+const MMIO_WINDOW_START: u64 = 0xFFFF_D000_0000_0000;
+const MMIO_WINDOW_SIZE: u64 = 0x0000_0100_0000_0000;
+/// Firmware tables mapped through `window::map` live here.
+pub const ACPI_WINDOW_START: u64 = 0xFFFF_E000_0000_0000;
+/// Kernel stacks from `stack::Stack` live here.
+pub const STACK_WINDOW_START: u64 = 0xFFFF_F000_0000_0000;
+
+// 0 while the boot identity map is active
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_WINDOW_START);
+static SHOOTDOWN_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+
+/// Hands out frames for new page tables straight from the global frame allocator.
+struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        frame::alloc_frame()
+    }
+}
+
+
+/// Called after a mapping was removed or its flags were changed on this CPU,
+/// with the first page and the number of 4 KiB pages affected.
+pub type ShootdownHook = fn(VirtAddr, u64);
+
+/// Registers the function that invalidates the TLB entries of other CPUs.
+pub fn set_shootdown_hook(hook: ShootdownHook) {
+    SHOOTDOWN_HOOK.store(hook as usize, Ordering::Release);
+}
+
+fn shootdown(start: VirtAddr, pages: u64) {
+    let hook = SHOOTDOWN_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: ShootdownHook = unsafe { core::mem::transmute(hook) };
+        hook(start, pages);
+    }
+}
+
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    without_interrupts(|| {
+        MAPPER.lock().as_ref()?.translate_addr(addr)
+    })
+}
+
+
+/// Builds the kernel page tables and switches CR3 to them.
+///
+/// The new address space contains all of physical memory at `PHYSICAL_MEMORY_OFFSET`,
+/// the kernel sections identity mapped with their ELF permissions and the mbi identity
+/// mapped read only. Anything else has to be mapped explicitly.
+pub unsafe fn init(mbi: &BootInformation) {
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
+    let p4_frame = frame::alloc_frame().expect("no frame for the level 4 table");
+    let p4_table = &mut *(p4_frame.start_address().as_u64() as *mut PageTable);
+    p4_table.zero();
+
+    // the boot tables identity map everything, so the new tables can be edited in place
+    let mut mapper = OffsetPageTable::new(p4_table, VirtAddr::new(0));
+
+    map_physical_memory(&mut mapper, mbi);
+    map_kernel(&mut mapper, mbi);
+
+    let mbi_flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    for page in page_range(VirtAddr::new(mbi.start_address() as u64), mbi.total_size() as u64) {
+        // the mbi can share its first page with the end of the kernel
+        if mapper.translate_page(page).is_ok() {
+            continue;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
+        mapper.map_to(page, frame, mbi_flags, &mut GlobalFrameAllocator).unwrap().ignore();
+    }
+
+    Cr3::write(p4_frame, Cr3Flags::empty());
+    PHYS_OFFSET.store(PHYSICAL_MEMORY_OFFSET, Ordering::Relaxed);
+    frame::FRAME_ALLOCATOR.lock().relocate(PHYSICAL_MEMORY_OFFSET);
+
+    let p4_table = &mut *(phys_to_virt(p4_frame.start_address()).as_mut_ptr::<PageTable>());
+    *MAPPER.lock() = Some(OffsetPageTable::new(p4_table, VirtAddr::new(PHYSICAL_MEMORY_OFFSET)));
+}
+
+unsafe fn map_physical_memory(mapper: &mut OffsetPageTable, mbi: &BootInformation) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    // always cover the 32 bit MMIO hole, the local and io apic live there
+    let memory_end = mbi.memory_map_tag()
+        .unwrap()
+        .memory_areas()
+        .iter()
+        .filter(|a| a.typ() != MemoryAreaType::Reserved)
+        .map(|a| a.end_address())
+        .max()
+        .unwrap()
+        .max(0x1_0000_0000);
+
+    let has_1gib_pages = CPUID
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_1gib_pages());
+
+    if has_1gib_pages {
+        for addr in (0..memory_end).step_by(Size1GiB::SIZE as usize) {
+            let page = Page::<Size1GiB>::containing_address(VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr));
+            let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(addr));
+            mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).unwrap().ignore();
+        }
+    } else {
+        for addr in (0..memory_end).step_by(Size2MiB::SIZE as usize) {
+            let page = Page::<Size2MiB>::containing_address(VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr));
+            let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(addr));
+            mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).unwrap().ignore();
+        }
+    }
+}
+
+unsafe fn map_kernel(mapper: &mut OffsetPageTable, mbi: &BootInformation) {
+    let sections = || mbi.elf_sections().unwrap().filter(|s| s.is_allocated());
+    let start = sections().map(|s| s.start_address()).min().unwrap();
+    let end = sections().map(|s| s.end_address()).max().unwrap();
+
+    // sections aren't page aligned, so a page gets the union of the permissions of every section it touches
+    for page in page_range(VirtAddr::new(start), end - start) {
+        let page_start = page.start_address().as_u64();
+        let page_end = page_start + FRAME_SIZE;
+
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        for section in sections().filter(|s| s.start_address() < page_end && s.end_address() > page_start) {
+            if section.flags().contains(ElfSectionFlags::WRITABLE) {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if section.flags().contains(ElfSectionFlags::EXECUTABLE) {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+        }
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(page_start));
+        mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).unwrap().ignore();
+    }
+}
+
+
+/// Maps `page` to `frame` in the kernel address space.
+pub unsafe fn map<S: PageSize>(page: Page<S>, frame: PhysFrame<S>, flags: PageTableFlags) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("paging not initialized");
+        mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, &mut GlobalFrameAllocator)
+            .map(|flush| flush.flush())
+    })
+}
+
+/// Removes the mapping of `page` and returns the frame it pointed to, the frame is not freed.
+pub unsafe fn unmap<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let frame = without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("paging not initialized");
+        mapper.unmap(page).map(|(frame, flush)| {
+            flush.flush();
+            frame
+        })
+    })?;
+
+    shootdown(page.start_address(), S::SIZE / Size4KiB::SIZE);
+    Ok(frame)
+}
+
+/// Replaces the flags of an already mapped `page`.
+pub unsafe fn protect<S: PageSize>(page: Page<S>, flags: PageTableFlags) -> Result<(), FlagUpdateError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let flags = match S::SIZE == Size4KiB::SIZE {
+        true => flags | PageTableFlags::PRESENT,
+        false => flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
+    };
+
+    without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("paging not initialized");
+        mapper.update_flags(page, flags).map(|flush| flush.flush())
+    })?;
+
+    shootdown(page.start_address(), S::SIZE / Size4KiB::SIZE);
+    Ok(())
+}
+
+/// Maps `size` bytes of device registers at `addr` uncached into the MMIO window.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> VirtAddr {
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    let offset = addr.as_u64() % FRAME_SIZE;
+    let len = (offset + size + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+    let virt_start = MMIO_NEXT.fetch_add(len, Ordering::Relaxed);
+    assert!(virt_start + len <= MMIO_WINDOW_START + MMIO_WINDOW_SIZE, "MMIO window exhausted");
+
+    for i in 0..len / FRAME_SIZE {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt_start + i * FRAME_SIZE));
+        let frame = PhysFrame::containing_address(addr - offset + i * FRAME_SIZE);
+        unsafe { map(page, frame, flags).unwrap() };
+    }
+
+    VirtAddr::new(virt_start + offset)
+}
+
+fn page_range(start: VirtAddr, size: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+    Page::range_inclusive(first, last)
+}
@@ -0,0 +1,63 @@
+use super::{frame, paging, paging::STACK_WINDOW_START, window::VirtualWindow, FRAME_SIZE};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+
+// 256 MiB of address space, a 64 KiB stack takes 17 pages of it
+static STACK_WINDOW: Mutex<VirtualWindow<1024>> = Mutex::new(VirtualWindow::new(STACK_WINDOW_START));
+
+
+/// Kernel stack mapped in its own window with an unmapped guard page below it, so an
+/// overflow faults instead of overwriting other memory. Unmapped again when dropped.
+pub struct Stack {
+    // lowest mapped address, the guard page is right below
+    bottom: VirtAddr,
+    pages: usize,
+    // less than `pages` only while `new` maps the stack
+    mapped: usize,
+}
+
+impl Stack {
+    /// Maps a stack of at least `size` bytes, `None` when there is no memory or address space left.
+    pub fn new(size: usize) -> Option<Self> {
+        let pages = (size as u64).div_ceil(FRAME_SIZE) as usize;
+        let guard = without_interrupts(|| STACK_WINDOW.lock().alloc(pages + 1))?;
+        let mut stack = Stack {
+            bottom: guard + FRAME_SIZE,
+            pages,
+            mapped: 0,
+        };
+
+        // on failure dropping the stack unmaps the pages mapped so far
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        while stack.mapped < pages {
+            let page = Page::<Size4KiB>::containing_address(stack.bottom + stack.mapped as u64 * FRAME_SIZE);
+            let frame = frame::alloc_frame()?;
+            unsafe { paging::map(page, frame, flags).unwrap() };
+            stack.mapped += 1;
+        }
+        Some(stack)
+    }
+
+    /// One past the highest byte, the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.pages as u64 * FRAME_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for index in 0..self.mapped as u64 {
+            let page = Page::<Size4KiB>::containing_address(self.bottom + index * FRAME_SIZE);
+            let frame = unsafe { paging::unmap(page).unwrap() };
+            frame::free_frame(frame);
+        }
+        without_interrupts(|| {
+            STACK_WINDOW.lock().free(self.bottom - FRAME_SIZE, self.pages + 1);
+        });
+    }
+}
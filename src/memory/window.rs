@@ -0,0 +1,99 @@
+use super::{paging, FRAME_SIZE};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+
+/// Region of kernel virtual address space for short lived mappings of physical memory.
+///
+/// Every 4 KiB page of the window has one bit in `used`, so it can hold `WORDS * 64` pages.
+pub struct VirtualWindow<const WORDS: usize> {
+    start: u64,
+    used: [u64; WORDS],
+}
+
+impl<const WORDS: usize> VirtualWindow<WORDS> {
+    pub const fn new(start: u64) -> Self {
+        Self {
+            start,
+            used: [0; WORDS],
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        match used {
+            true => self.used[index / 64] |= 1 << (index % 64),
+            false => self.used[index / 64] &= !(1 << (index % 64)),
+        }
+    }
+
+    /// Reserves `pages` consecutive pages and returns the address of the first one.
+    pub(super) fn alloc(&mut self, pages: usize) -> Option<VirtAddr> {
+        let mut start = 0;
+        while start + pages <= WORDS * 64 {
+            match (start..start + pages).rev().find(|&i| self.is_used(i)) {
+                Some(used) => start = used + 1,
+                None => {
+                    for index in start..start + pages {
+                        self.set_used(index, true);
+                    }
+                    return Some(VirtAddr::new(self.start + (start as u64) * FRAME_SIZE));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub(super) fn free(&mut self, addr: VirtAddr, pages: usize) {
+        let start = ((addr.as_u64() - self.start) / FRAME_SIZE) as usize;
+        for index in start..start + pages {
+            self.set_used(index, false);
+        }
+    }
+}
+
+
+/// Maps `size` bytes of physical memory at `addr` into `window` and returns the address of `addr` in it.
+pub fn map<const WORDS: usize>(
+    window: &Mutex<VirtualWindow<WORDS>>,
+    addr: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Option<VirtAddr> {
+    let offset = addr.as_u64() % FRAME_SIZE;
+    let pages = ((offset + size as u64 + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+    let virt_start = x86_64::instructions::interrupts::without_interrupts(|| {
+        window.lock().alloc(pages)
+    })?;
+
+    for i in 0..pages as u64 {
+        let page = Page::<Size4KiB>::containing_address(virt_start + i * FRAME_SIZE);
+        let frame = PhysFrame::containing_address(addr - offset + i * FRAME_SIZE);
+        unsafe { paging::map(page, frame, flags).unwrap() };
+    }
+
+    Some(virt_start + offset)
+}
+
+/// Undoes a `map` of the same `size` that returned `addr`.
+pub fn unmap<const WORDS: usize>(window: &Mutex<VirtualWindow<WORDS>>, addr: VirtAddr, size: usize) {
+    let offset = addr.as_u64() % FRAME_SIZE;
+    let pages = ((offset + size as u64 + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+    let virt_start = addr - offset;
+
+    for i in 0..pages as u64 {
+        let page = Page::<Size4KiB>::containing_address(virt_start + i * FRAME_SIZE);
+        unsafe { paging::unmap(page).unwrap() };
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        window.lock().free(virt_start, pages);
+    });
+}
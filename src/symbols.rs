@@ -0,0 +1,122 @@
+use core::fmt;
+use multiboot2::{BootInformation, ElfSectionType};
+use spin::Once;
+use x86_64::PhysAddr;
+
+
+const SYMBOL_SIZE: usize = 24;
+const STT_FUNC: u8 = 2;
+
+
+#[derive(Clone, Copy)]
+struct SymbolTable {
+    symtab: PhysAddr,
+    symtab_len: usize,
+    strtab: PhysAddr,
+    strtab_len: usize,
+}
+
+static SYMBOL_TABLE: Once<SymbolTable> = Once::new();
+
+
+/// Remembers where GRUB loaded `.symtab` and `.strtab`.
+///
+/// Has to run while the boot identity map is still active, reading the section names
+/// goes through a pointer into the physical address of `.shstrtab`.
+pub fn init(mbi: &BootInformation) {
+    let mut symtab = None;
+    let mut strtab = None;
+    for section in mbi.elf_sections().unwrap() {
+        if section.start_address() == 0 {
+            continue;
+        }
+
+        match (section.section_type(), section.name()) {
+            (ElfSectionType::LinkerSymbolTable, _) => symtab = Some(section),
+            (ElfSectionType::StringTable, Ok(".strtab")) => strtab = Some(section),
+            _ => {}
+        }
+    }
+
+    if let (Some(symtab), Some(strtab)) = (symtab, strtab) {
+        SYMBOL_TABLE.call_once(|| SymbolTable {
+            symtab: PhysAddr::new(symtab.start_address()),
+            symtab_len: symtab.size() as usize,
+            strtab: PhysAddr::new(strtab.start_address()),
+            strtab_len: strtab.size() as usize,
+        });
+    }
+}
+
+
+/// Function symbol containing an address, displayed as `name+0xoffset`.
+pub struct Symbol {
+    name: &'static str,
+    offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        demangle(self.name, f)?;
+        write!(f, "+{:#X}", self.offset)
+    }
+}
+
+/// Finds the function containing `addr`, `None` if the kernel was loaded without a symbol table.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let table = SYMBOL_TABLE.get()?;
+    let symtab = unsafe {
+        core::slice::from_raw_parts(crate::memory::phys_to_virt(table.symtab).as_ptr::<u8>(), table.symtab_len)
+    };
+    let strtab = unsafe {
+        core::slice::from_raw_parts(crate::memory::phys_to_virt(table.strtab).as_ptr::<u8>(), table.strtab_len)
+    };
+
+    let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+
+    let (name_offset, value) = symtab.chunks_exact(SYMBOL_SIZE).find_map(|sym| {
+        let name = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+        let info = sym[4];
+        let value = read_u64(&sym[8..16]);
+        let size = read_u64(&sym[16..24]);
+
+        match info & 0xF == STT_FUNC && value <= addr && addr < value + size.max(1) {
+            true => Some((name, value)),
+            false => None,
+        }
+    })?;
+
+    let name_bytes = &strtab[name_offset..];
+    let name_len = name_bytes.iter().position(|&b| b == 0)?;
+    let name = core::str::from_utf8(&name_bytes[..name_len]).ok()?;
+
+    Some(Symbol {
+        name,
+        offset: addr - value,
+    })
+}
+
+/// Prints legacy mangled names (`_ZN6kernel4kmain17h0123456789abcdefE`) as `kernel::kmain`.
+fn demangle(name: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return f.write_str(name);
+    };
+
+    let mut first = true;
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()) {
+        let Ok(len) = rest[..len_end].parse::<usize>() else { break };
+        let Some(segment) = rest.get(len_end..len_end + len) else { break };
+        rest = &rest[len_end + len..];
+
+        // the trailing hash
+        if rest == "E" && segment.starts_with('h') && segment.len() == 17 {
+            break;
+        }
+        if !first {
+            f.write_str("::")?;
+        }
+        f.write_str(segment)?;
+        first = false;
+    }
+    Ok(())
+}
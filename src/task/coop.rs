@@ -0,0 +1,62 @@
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+};
+
+
+/// How many budget units a task may use in one poll before it has to yield.
+pub const TASK_POLL_BUDGET: u32 = 128;
+
+
+/// Gives the task a fresh budget, called by the executor before every poll.
+pub(crate) fn reset_budget() {
+    crate::percpu!(poll_budget).store(TASK_POLL_BUDGET, Ordering::Relaxed);
+}
+
+/// Uses one unit of the running task's budget.
+///
+/// Once it is used up the task is woken again and `Pending` is returned, so it goes
+/// to the back of its run queue. Leaf futures and streams that can stay ready for a
+/// long time call this first.
+pub fn poll_proceed(cx: &mut Context) -> Poll<()> {
+    let budget = crate::percpu!(poll_budget);
+    match budget.load(Ordering::Relaxed) {
+        0 => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        left => {
+            budget.store(left - 1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Yields only if the task used up its budget.
+pub async fn consume_budget() {
+    poll_fn(poll_proceed).await
+}
+
+/// Lets every other ready task of the same priority run before continuing.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -1,62 +1,150 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{coop, Priority, Task, TaskId};
+use crate::cpu::{self, percpu};
+use crate::interrupts::ipi;
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Waker};
+use core::time::Duration;
+use crossbeam_queue::SegQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 
+
+// every spawned task, the run queues only hold their IDs
+static TASKS: Mutex<BTreeMap<TaskId, Arc<TaskEntry>>> = Mutex::new(BTreeMap::new());
+// a bit per cpu index that is halted in `sleep_if_idle`
+static IDLE: AtomicU64 = AtomicU64::new(0);
+
+// every this many tasks the lowest ready priority runs, so background tasks don't starve
+const FAIRNESS_INTERVAL: u32 = 16;
+
+
+struct TaskEntry {
+    // locked while a cpu polls the task, so it never runs on two cpus at once
+    task: Mutex<Task>,
+    waker: Waker,
+    wake_state: Arc<TaskWaker>,
+    polls: AtomicU64,
+    poll_time_ns: AtomicU64,
+}
+
+
+/// Counters of one task since it was spawned.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStats {
+    pub priority: Priority,
+    pub polls: u64,
+    /// Time spent inside the task's `poll`.
+    pub poll_time: Duration,
+    pub wakeups: u64,
+}
+
+impl TaskEntry {
+    fn stats(&self) -> TaskStats {
+        TaskStats {
+            priority: self.wake_state.priority,
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(self.poll_time_ns.load(Ordering::Relaxed)),
+            wakeups: self.wake_state.wakeups.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of a task that has not finished yet.
+pub fn task_stats(task_id: TaskId) -> Option<TaskStats> {
+    without_interrupts(|| TASKS.lock().get(&task_id).map(|entry| entry.stats()))
+}
+
+/// Statistics of every unfinished task.
+pub fn stats() -> Vec<(TaskId, TaskStats)> {
+    without_interrupts(|| TASKS.lock().iter().map(|(id, entry)| (*id, entry.stats())).collect())
+}
+
+
+/// Handle to the executor shared by every CPU.
+///
+/// Each CPU runs tasks from its own run queue and steals from the others once it is empty.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    _private: (),
 }
 
 impl Executor {
     pub fn new() -> Self {
-        Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
-        }
+        Executor { _private: () }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn(&self, task: Task) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already in tasks");
-        }
-        self.task_queue.push(task_id).expect("queue full");
+        let wake_state = Arc::new(TaskWaker {
+            task_id,
+            priority: task.priority,
+            queued: AtomicBool::new(false),
+            wakeups: AtomicU64::new(0),
+        });
+        let entry = Arc::new(TaskEntry {
+            task: Mutex::new(task),
+            waker: Waker::from(wake_state.clone()),
+            wake_state,
+            polls: AtomicU64::new(0),
+            poll_time_ns: AtomicU64::new(0),
+        });
+        without_interrupts(|| {
+            if TASKS.lock().insert(task_id, entry.clone()).is_some() {
+                panic!("task with same ID already in tasks");
+            }
+        });
+        entry.wake_state.schedule();
     }
 
-    pub fn run(&mut self) -> ! {
+    /// Runs tasks on the calling CPU forever.
+    pub fn run(&self) -> ! {
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+    fn run_ready_tasks(&self) {
+        let percpu = percpu::current();
+        let mut picked = 0u32;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
+        loop {
+            picked = picked.wrapping_add(1);
+            let lowest_first = picked % FAIRNESS_INTERVAL == 0;
+            let task_id = match pop(&percpu.run_queues, lowest_first).or_else(steal) {
+                Some(task_id) => task_id,
+                None => break,
+            };
+
+            let entry = match without_interrupts(|| TASKS.lock().get(&task_id).cloned()) {
+                Some(entry) => entry,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+
+            // another cpu is polling it, the wake that queued it must not get lost
+            let mut task = match entry.task.try_lock() {
+                Some(task) => task,
+                None => {
+                    schedule(task_id, entry.wake_state.priority);
+                    continue;
                 }
-                Poll::Pending => {}
+            };
+
+            // cleared before polling, a wake during the poll queues the task again
+            entry.wake_state.queued.store(false, Ordering::SeqCst);
+            percpu.set_current_task(Some(task_id));
+            coop::reset_budget();
+            let start = crate::time::now();
+            let mut context = Context::from_waker(&entry.waker);
+            let result = task.poll(&mut context);
+            entry.poll_time_ns.fetch_add(crate::time::now() - start, Ordering::Relaxed);
+            entry.polls.fetch_add(1, Ordering::Relaxed);
+            percpu.set_current_task(None);
+
+            if result.is_ready() {
+                // task done -> remove it and its waker
+                drop(task);
+                without_interrupts(|| TASKS.lock().remove(&task_id));
             }
         }
     }
@@ -64,30 +152,91 @@ impl Executor {
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
+        let percpu = percpu::current();
+        let bit = 1 << percpu.index;
+
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
+        IDLE.fetch_or(bit, Ordering::SeqCst);
+        // a task queued anywhere before the idle bit was set has to be seen here
+        if work_available() {
+            interrupts::enable();
+        } else if crate::thread::has_ready() {
+            // the executor runs as a thread, let the others have the cpu instead of halting
+            IDLE.fetch_and(!bit, Ordering::SeqCst);
+            crate::thread::yield_now();
             interrupts::enable();
+            return;
+        } else {
+            // tickless: the timer only fires for the next pending timer future or time slice
+            crate::thread::rearm_timer();
+            enable_and_hlt();
         }
+        IDLE.fetch_and(!bit, Ordering::SeqCst);
     }
 }
 
+
+fn cpu_count() -> usize {
+    cpu::cpus().len().max(1)
+}
+
+fn work_available() -> bool {
+    (0..cpu_count())
+        .filter_map(percpu::get)
+        .any(|block| block.run_queues.iter().any(|queue| !queue.is_empty()))
+}
+
+/// Takes the first task of the highest ready priority, or of the lowest one.
+fn pop(queues: &[SegQueue<TaskId>; Priority::COUNT], lowest_first: bool) -> Option<TaskId> {
+    match lowest_first {
+        true => queues.iter().rev().find_map(SegQueue::pop),
+        false => queues.iter().find_map(SegQueue::pop),
+    }
+}
+
+/// Takes a task from the run queues of another CPU, starting with the next index.
+fn steal() -> Option<TaskId> {
+    let current = cpu::current_index();
+    let count = cpu_count();
+    (1..count)
+        .map(|offset| (current + offset) % count)
+        .find_map(|index| pop(&percpu::get(index)?.run_queues, false))
+}
+
+/// Queues a task on the calling CPU and wakes an idle CPU to steal it.
+fn schedule(task_id: TaskId, priority: Priority) {
+    let current = percpu::current();
+    current.run_queues[priority as usize].push(task_id);
+    kick_idle(current.index);
+}
+
+/// Sends a reschedule IPI to one idle CPU other than `except`.
+fn kick_idle(except: usize) {
+    let idle = IDLE.load(Ordering::SeqCst) & !(1 << except);
+    if idle != 0 {
+        let _ = ipi::reschedule(idle.trailing_zeros() as usize);
+    }
+}
+
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    // set while the task sits in a run queue, so it is queued at most once
+    queued: AtomicBool,
+    wakeups: AtomicU64,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn schedule(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            schedule(self.task_id, self.priority);
+        }
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.schedule();
     }
 }
 
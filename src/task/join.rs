@@ -0,0 +1,139 @@
+use super::TaskId;
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted before it finished.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+
+/// State shared between a spawned task and its `JoinHandle`.
+pub(super) struct JoinState<T> {
+    output: Mutex<Option<Result<T, JoinError>>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    // the handle waiting for the output
+    join_waker: AtomicWaker,
+    // the task itself, woken by `abort` so it notices
+    task_waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(JoinState {
+            output: Mutex::new(None),
+            finished: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            join_waker: AtomicWaker::new(),
+            task_waker: AtomicWaker::new(),
+        })
+    }
+
+    fn finish(&self, output: Result<T, JoinError>) {
+        *self.output.lock() = Some(output);
+        self.finished.store(true, Ordering::Release);
+        self.join_waker.wake();
+    }
+}
+
+
+/// The future actually run by the executor, stores the output for the handle.
+pub(super) struct Joinable<F: Future> {
+    pub(super) future: Pin<Box<F>>,
+    pub(super) state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // registered first, an abort after the check still wakes the task
+        self.state.task_waker.register(cx.waker());
+        if self.state.aborted.load(Ordering::Acquire) {
+            self.state.finish(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(output) => {
+                self.state.finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+/// Owned permission to await the output of a task started with `task::spawn`.
+///
+/// Dropping the handle detaches the task, it keeps running and its output is dropped.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(id: TaskId, state: Arc<JoinState<T>>) -> Self {
+        JoinHandle { id, state }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// Stops the task the next time it would be polled, awaiting the handle then
+    /// yields `JoinError::Cancelled`. Does nothing if the task already finished.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+
+    /// Lets the task run to completion without anyone waiting for it.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.join_waker.register(cx.waker());
+        if !self.state.finished.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(output),
+            None => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("id", &self.id).finish()
+    }
+}
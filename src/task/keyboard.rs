@@ -1,4 +1,4 @@
-use crate::{print, println};
+use crate::print;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
@@ -21,12 +21,12 @@ static WAKER: AtomicWaker = AtomicWaker::new();
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            log::warn!("scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        log::warn!("scancode queue uninitialized");
     }
 }
 
@@ -6,29 +6,86 @@ use core::{
     task::{Context, Poll},
 };
 
+pub mod coop;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod serial;
+pub mod sync;
+pub mod time;
+
+pub use coop::{consume_budget, yield_now};
+pub use join::{JoinError, JoinHandle};
+
+/// Scheduling class of a task, ready tasks of a higher class run first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Deferred work of interrupt handlers.
+    BottomHalf,
+    /// Tasks a user waits on, like input handling.
+    Interactive,
+    Normal,
+    Background,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+}
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::with_priority(future, Priority::Normal)
+    }
+
+    pub fn with_priority(future: impl Future<Output = ()> + Send + 'static, priority: Priority) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
             future: Box::pin(future),
         }
     }
 
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// Starts `future` as a new task on the executor, callable from inside other tasks.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with_priority(future, Priority::Normal)
+}
+
+pub fn spawn_with_priority<F>(future: F, priority: Priority) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = join::JoinState::new();
+    let task = Task::with_priority(join::Joinable {
+        future: Box::pin(future),
+        state: state.clone(),
+    }, priority);
+    let id = task.id;
+    executor::Executor::new().spawn(task);
+    JoinHandle::new(id, state)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(pub(crate) u64);
 
 impl TaskId {
     fn new() -> Self {
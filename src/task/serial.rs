@@ -0,0 +1,115 @@
+use crate::{com, klog};
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+
+/// Called by the serial interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if let Err(_) = queue.push(byte) {
+            log::warn!("serial queue full; dropping serial input");
+        } else {
+            WAKER.wake();
+        }
+    }
+    // bytes arriving before the stream exists are dropped
+}
+
+/// Bytes received on the debug shell's port, see `com::shell_port`.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(256))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE
+            .try_get()
+            .expect("serial queue not initialized");
+
+        // fast path
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+const PROMPT: &str = "> ";
+
+/// Line based debug shell on the shell port, see `run_command`.
+pub async fn shell() {
+    let (port, _) = com::shell_port().expect("no serial port for the shell");
+    let mut bytes = SerialStream::new();
+    let mut line = String::new();
+    com::write_port(port, format_args!("{}", PROMPT));
+
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            // terminals send a carriage return for enter
+            b'\r' => {
+                com::write_port(port, format_args!("\n"));
+                run_command(port, line.trim());
+                line.clear();
+                com::write_port(port, format_args!("{}", PROMPT));
+            }
+            // backspace or delete
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    com::write_port(port, format_args!("\x08 \x08"));
+                }
+            }
+            0x20..=0x7E => {
+                line.push(byte as char);
+                com::write_port(port, format_args!("{}", byte as char));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn run_command(port: usize, command: &str) {
+    match command {
+        "" => {}
+        "dmesg" => {
+            // a line at a time, the port is locked with interrupts disabled while writing
+            for line in klog::dmesg().lines() {
+                com::write_port(port, format_args!("{}\n", line));
+            }
+        }
+        "help" => com::write_port(port, format_args!("commands: dmesg, help\n")),
+        _ => com::write_port(port, format_args!("unknown command {:?}, try help\n", command)),
+    }
+}
@@ -0,0 +1,205 @@
+//! Multi-producer, multi-consumer channel where every receiver sees every value.
+//!
+//! The channel keeps the last `capacity` values, a receiver that falls further behind
+//! skips the oldest ones and is told how many it missed.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::poll_fn,
+    task::{Context, Poll, Waker},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+
+/// There are no receivers, the value is handed back.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and the receiver has seen every value.
+    Closed,
+    /// The receiver skipped this many values that were overwritten.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+
+struct Shared<T> {
+    state: spin::Mutex<State<T>>,
+    capacity: usize,
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    // sequence number of the first value in `buffer`
+    head: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+impl<T> Shared<T> {
+    fn wake_all(&self) {
+        let wakers = without_interrupts(|| core::mem::take(&mut self.state.lock().waiters));
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            head: 0,
+            senders: 1,
+            receivers: 1,
+            waiters: Vec::new(),
+        }),
+        capacity,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0 })
+}
+
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues the value for every receiver, returns how many receivers there are.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let receivers = without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.buffer.len() == self.shared.capacity {
+                state.buffer.pop_front();
+                state.head += 1;
+            }
+            state.buffer.push_back(value);
+            Ok(state.receivers)
+        })?;
+        self.shared.wake_all();
+        Ok(receivers)
+    }
+
+    /// New receiver that sees the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            state.receivers += 1;
+            state.tail()
+        });
+        Receiver { shared: self.shared.clone(), next }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        without_interrupts(|| self.shared.state.lock().receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        without_interrupts(|| self.shared.state.lock().senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        });
+        if last {
+            self.shared.wake_all();
+        }
+    }
+}
+
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // sequence number of the next value this receiver wants
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        without_interrupts(|| {
+            let state = self.shared.state.lock();
+            match take(&mut self.next, &state) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(RecvError::Lagged(missed))) => Err(TryRecvError::Lagged(missed)),
+                Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        })
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            match take(&mut self.next, &state) {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    if !state.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        state.waiters.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+/// Next value for a receiver at `next` from the buffer.
+fn take<T: Clone>(next: &mut u64, state: &State<T>) -> Option<Result<T, RecvError>> {
+    if *next < state.head {
+        let missed = state.head - *next;
+        *next = state.head;
+        return Some(Err(RecvError::Lagged(missed)));
+    }
+    if *next < state.tail() {
+        let value = state.buffer[(*next - state.head) as usize].clone();
+        *next += 1;
+        return Some(Ok(value));
+    }
+    match state.senders {
+        0 => Some(Err(RecvError::Closed)),
+        _ => None,
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        without_interrupts(|| self.shared.state.lock().receivers -= 1);
+    }
+}
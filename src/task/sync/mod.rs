@@ -0,0 +1,16 @@
+//! Synchronization for tasks, waiting tasks sleep until they are woken instead of spinning.
+//!
+//! Guards of the locks here may be held across `.await`.
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+pub mod rwlock;
+pub mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
@@ -0,0 +1,245 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! Both kinds share the `Receiver`. An `UnboundedSender` never waits, so it also works
+//! in interrupt handlers.
+
+use super::semaphore::{Semaphore, TryAcquireError};
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::poll_fn,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::SegQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+
+/// The receiver is gone, the value is handed back.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and the channel is empty.
+    Disconnected,
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+
+struct Chan<T> {
+    queue: SegQueue<T>,
+    // free slots of a bounded channel, closed together with the receiver
+    slots: Option<Semaphore>,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+    recv_waker: AtomicWaker,
+}
+
+impl<T> Chan<T> {
+    fn new(slots: Option<Semaphore>) -> Arc<Self> {
+        Arc::new(Chan {
+            queue: SegQueue::new(),
+            slots,
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+            recv_waker: AtomicWaker::new(),
+        })
+    }
+
+    fn push(&self, value: T) {
+        self.queue.push(value);
+        self.recv_waker.wake();
+    }
+
+    fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn drop_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.recv_waker.wake();
+        }
+    }
+}
+
+
+/// Channel holding at most `capacity` values, `Sender::send` waits while it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be non-zero");
+    let chan = Chan::new(Some(Semaphore::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Waits for a free slot, fails once the receiver is gone.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let slots = self.chan.slots.as_ref().unwrap();
+        match slots.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.chan.push(value);
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let slots = self.chan.slots.as_ref().unwrap();
+        match slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+        }
+        self.chan.push(value);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.is_closed() {
+            return Err(SendError(value));
+        }
+        self.chan.push(value);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Next value, `None` once every sender is gone and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+        match self.chan.senders.load(Ordering::Acquire) {
+            // a sender may have pushed right before it was dropped
+            0 => self.pop().ok_or(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.chan.recv_waker.register(cx.waker());
+        if let Some(value) = self.pop() {
+            return Poll::Ready(Some(value));
+        }
+        match self.chan.senders.load(Ordering::Acquire) {
+            0 => Poll::Ready(self.pop()),
+            _ => Poll::Pending,
+        }
+    }
+
+    /// Stops new values from being sent, the ones already queued can still be received.
+    pub fn close(&mut self) {
+        self.chan.closed.store(true, Ordering::Release);
+        if let Some(slots) = &self.chan.slots {
+            slots.close();
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = self.chan.queue.pop()?;
+        if let Some(slots) = &self.chan.slots {
+            slots.add_permits(1);
+        }
+        Some(value)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
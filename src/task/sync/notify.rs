@@ -0,0 +1,137 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+
+
+const WAITING: u8 = 0;
+const NOTIFIED_ONE: u8 = 1;
+const NOTIFIED_ALL: u8 = 2;
+
+
+/// Wakes waiting tasks without passing data, usable from interrupt handlers.
+///
+/// `notify_one` with nobody waiting is remembered, the next `notified` completes at once.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+struct Waiter {
+    notified: AtomicU8,
+    waker: AtomicWaker,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wakes the task waiting longest, or stores a permit for the next one.
+    pub fn notify_one(&self) {
+        let waker = without_interrupts(|| {
+            let mut state = self.state.lock();
+            match state.waiters.pop_front() {
+                Some(waiter) => {
+                    waiter.notified.store(NOTIFIED_ONE, Ordering::Release);
+                    waiter.waker.take()
+                }
+                None => {
+                    state.permit = true;
+                    None
+                }
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every task waiting right now, no permit is stored.
+    pub fn notify_waiters(&self) {
+        let wakers: Vec<Waker> = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.waiters.drain(..).filter_map(|waiter| {
+                waiter.notified.store(NOTIFIED_ALL, Ordering::Release);
+                waiter.waker.take()
+            }).collect()
+        });
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, waiter: None }
+    }
+}
+
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if let Some(waiter) = &self.waiter {
+            waiter.waker.register(cx.waker());
+            if waiter.notified.load(Ordering::Acquire) == WAITING {
+                return Poll::Pending;
+            }
+            self.waiter = None;
+            return Poll::Ready(());
+        }
+
+        let notify = self.notify;
+        without_interrupts(|| {
+            let mut state = notify.state.lock();
+            if state.permit {
+                state.permit = false;
+                return Poll::Ready(());
+            }
+
+            let waiter = Arc::new(Waiter {
+                notified: AtomicU8::new(WAITING),
+                waker: AtomicWaker::new(),
+            });
+            waiter.waker.register(cx.waker());
+            state.waiters.push_back(waiter.clone());
+            self.waiter = Some(waiter);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+
+        without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+            state.waiters.retain(|queued| !Arc::ptr_eq(queued, &waiter));
+        });
+        // a `notify_one` meant for this waiter must not get lost
+        if waiter.notified.load(Ordering::Acquire) == NOTIFIED_ONE {
+            self.notify.notify_one();
+        }
+    }
+}
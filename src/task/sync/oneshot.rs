@@ -0,0 +1,94 @@
+//! Channel for sending a single value, e.g. the reply to a request.
+
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+
+struct Inner<T> {
+    value: spin::Mutex<Option<T>>,
+    // set by the side that finished, either by sending or by being dropped
+    sender_done: AtomicBool,
+    receiver_dropped: AtomicBool,
+    waker: AtomicWaker,
+}
+
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        value: spin::Mutex::new(None),
+        sender_done: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands the value to the receiver, it is given back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+        // dropping self afterwards marks the channel done and wakes the receiver
+        without_interrupts(|| *self.inner.value.lock() = Some(value));
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.receiver_dropped.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.sender_done.store(true, Ordering::Release);
+        self.inner.waker.wake();
+    }
+}
+
+
+/// Future resolving to the sent value.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn take(&self) -> Option<T> {
+        without_interrupts(|| self.inner.value.lock().take())
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.inner.waker.register(cx.waker());
+        if !self.inner.sender_done.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        Poll::Ready(self.take().ok_or(RecvError))
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.receiver_dropped.store(true, Ordering::Release);
+    }
+}
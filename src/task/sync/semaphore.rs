@@ -0,0 +1,224 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+
+
+/// Returned when acquiring from a closed semaphore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+
+/// Counting semaphore, waiting tasks are served in FIFO order.
+///
+/// Releasing permits is allowed in interrupt handlers.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+struct Waiter {
+    needed: usize,
+    // the permits were handed over by `add_permits`
+    granted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        without_interrupts(|| self.state.lock().permits)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        without_interrupts(|| self.state.lock().closed)
+    }
+
+    /// Fails every waiting and future acquire, permits already handed out stay valid.
+    pub fn close(&self) {
+        let wakers: Vec<Waker> = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.closed = true;
+            state.waiters.drain(..).filter_map(|waiter| waiter.waker.take()).collect()
+        });
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: permits,
+            waiter: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.closed {
+                return Err(TryAcquireError::Closed);
+            }
+            // waiting tasks go first
+            if !state.waiters.is_empty() || state.permits < permits {
+                return Err(TryAcquireError::NoPermits);
+            }
+            state.permits -= permits;
+            Ok(SemaphorePermit { semaphore: self, permits })
+        })
+    }
+
+    /// Adds permits, handing them to waiting tasks first.
+    pub fn add_permits(&self, permits: usize) {
+        let mut wakers = Vec::new();
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.permits += permits;
+            while let Some(waiter) = state.waiters.front() {
+                if waiter.needed > state.permits {
+                    break;
+                }
+                state.permits -= waiter.needed;
+                let waiter = state.waiters.pop_front().unwrap();
+                waiter.granted.store(true, Ordering::Release);
+                wakers.extend(waiter.waker.take());
+            }
+        });
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore").field("permits", &self.available_permits()).finish()
+    }
+}
+
+
+/// Permits taken from a `Semaphore`, given back when dropped.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken, the semaphore's count stays lowered.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+
+        if let Some(waiter) = &self.waiter {
+            waiter.waker.register(cx.waker());
+            if waiter.granted.load(Ordering::Acquire) {
+                self.waiter = None;
+                return Poll::Ready(Ok(SemaphorePermit { semaphore, permits: needed }));
+            }
+            if semaphore.is_closed() {
+                self.waiter = None;
+                return Poll::Ready(Err(AcquireError));
+            }
+            return Poll::Pending;
+        }
+
+        without_interrupts(|| {
+            let mut state = semaphore.state.lock();
+            if state.closed {
+                return Poll::Ready(Err(AcquireError));
+            }
+            if state.waiters.is_empty() && state.permits >= needed {
+                state.permits -= needed;
+                return Poll::Ready(Ok(SemaphorePermit { semaphore, permits: needed }));
+            }
+
+            let waiter = Arc::new(Waiter {
+                needed,
+                granted: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+            });
+            waiter.waker.register(cx.waker());
+            state.waiters.push_back(waiter.clone());
+            self.waiter = Some(waiter);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+
+        // still queued -> leave the queue, otherwise give back what was granted
+        let queued = without_interrupts(|| {
+            let mut state = self.semaphore.state.lock();
+            let position = state.waiters.iter().position(|queued| Arc::ptr_eq(queued, &waiter));
+            position.map(|position| state.waiters.remove(position)).is_some()
+        });
+        match queued {
+            // a smaller request behind it may fit now
+            true => self.semaphore.add_permits(0),
+            false if waiter.granted.load(Ordering::Acquire) => self.semaphore.add_permits(self.needed),
+            false => {}
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::time;
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::{
+    future::{select, Either},
+    stream::Stream,
+    task::AtomicWaker,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+
+const WHEEL_SLOTS: usize = 256;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+
+struct Timer {
+    // nanoseconds since boot, see `time::now`
+    deadline: u64,
+    waker: AtomicWaker,
+    fired: AtomicBool,
+}
+
+/// Hashed timer wheel with one slot per tick of `time::tick_frequency`.
+///
+/// Timers further away than `WHEEL_SLOTS` ticks stay in their slot for more than one round.
+struct TimerWheel {
+    slots: [Vec<Arc<Timer>>; WHEEL_SLOTS],
+    // last tick whose slot was checked
+    cursor: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Arc<Timer>> = Vec::new();
+        Self {
+            slots: [EMPTY; WHEEL_SLOTS],
+            cursor: 0,
+        }
+    }
+
+    fn tick_of(ns: u64) -> u64 {
+        let hz = time::tick_frequency().max(1) as u128;
+        (ns as u128 * hz).div_ceil(NANOS_PER_SEC as u128) as u64
+    }
+
+    /// Returns whether the timer is now the earliest one.
+    fn insert(&mut self, timer: Arc<Timer>) -> bool {
+        let earliest = self.next_deadline().map_or(true, |next| timer.deadline < next);
+        // slots up to the cursor were already checked this round
+        let tick = Self::tick_of(timer.deadline).max(self.cursor + 1);
+        self.slots[tick as usize % WHEEL_SLOTS].push(timer);
+        earliest
+    }
+
+    /// Checks every slot up to the current time, the LAPIC timer may have skipped ticks
+    /// or be tickless.
+    fn advance(&mut self) {
+        let now = time::now();
+        let target = Self::tick_of(now);
+        let first = self.cursor.max(target.saturating_sub(WHEEL_SLOTS as u64 - 1));
+
+        for tick in first..=target {
+            self.slots[tick as usize % WHEEL_SLOTS].retain(|timer| {
+                // the future was dropped before it expired
+                if Arc::strong_count(timer) == 1 {
+                    return false;
+                }
+                if timer.deadline > now {
+                    return true;
+                }
+                timer.fired.store(true, Ordering::Release);
+                timer.waker.wake();
+                false
+            });
+        }
+        self.cursor = self.cursor.max(target);
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.slots.iter()
+            .flatten()
+            .filter(|timer| Arc::strong_count(timer) > 1)
+            .map(|timer| timer.deadline)
+            .min()
+    }
+}
+
+
+/// Called by the LAPIC timer interrupt handler
+///
+/// Must not block.
+pub(crate) fn on_tick() {
+    let mut wheel = WHEEL.lock();
+    wheel.advance();
+    // tickless, the timer has to fire again for the next timer even if the executor never idles
+    time::arm_deadline(wheel.next_deadline());
+}
+
+/// Earliest deadline of a pending timer future, used by the executor to arm the timer before idling.
+pub fn next_deadline() -> Option<u64> {
+    without_interrupts(|| WHEEL.lock().next_deadline())
+}
+
+
+/// Completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(time::now() + duration.as_nanos() as u64)
+}
+
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<Arc<Timer>>,
+}
+
+impl Sleep {
+    fn until(deadline: u64) -> Self {
+        Sleep {
+            deadline,
+            timer: None,
+        }
+    }
+
+    /// Time since boot at which the sleep completes.
+    pub fn deadline(&self) -> Duration {
+        Duration::from_nanos(self.deadline)
+    }
+
+    fn reset(&mut self, deadline: u64) {
+        self.deadline = deadline;
+        self.timer = None;
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::now() >= self.deadline {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+
+        match &self.timer {
+            Some(timer) => timer.waker.register(cx.waker()),
+            None => {
+                let timer = Arc::new(Timer {
+                    deadline: self.deadline,
+                    waker: AtomicWaker::new(),
+                    fired: AtomicBool::new(false),
+                });
+                timer.waker.register(cx.waker());
+                let earliest = without_interrupts(|| WHEEL.lock().insert(timer.clone()));
+                // only the bsp advances the wheel, it may be busy or have armed a later deadline
+                if earliest && time::is_tickless() {
+                    let _ = crate::interrupts::ipi::call_on(0, crate::thread::rearm_timer);
+                }
+                self.timer = Some(timer);
+            }
+        }
+
+        // the wheel may have fired before the new waker was registered
+        match self.timer.as_ref().unwrap().fired.load(Ordering::Acquire) {
+            true => {
+                self.timer = None;
+                Poll::Ready(())
+            }
+            false => Poll::Pending,
+        }
+    }
+}
+
+
+/// Yields every `period`, the first item is yielded after one period.
+///
+/// Missed periods are skipped instead of being yielded in a burst.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must be non-zero");
+    let period = period.as_nanos() as u64;
+
+    Interval {
+        period,
+        sleep: Sleep::until(time::now() + period),
+    }
+}
+
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Stream for Interval {
+    /// The deadline that was reached, as time since boot.
+    type Item = Duration;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Duration>> {
+        // a short period can stay ready forever
+        if super::coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let deadline = self.sleep.deadline;
+        let mut next = deadline + self.period;
+        let now = time::now();
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep.reset(next);
+
+        Poll::Ready(Some(Duration::from_nanos(deadline)))
+    }
+}
+
+
+/// Returned by `timeout` when the future didn't complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, it is dropped if the time runs out.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let future = pin!(future);
+    match select(future, sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
@@ -1,7 +1,23 @@
-use crate::{println, CPUID};
+use crate::memory::{frame::BitmapFrameAllocator, FRAME_SIZE};
+use crate::task::sync::{broadcast, mpsc, Semaphore, TryAcquireError};
+use crate::{com, klog, println, CPUID};
+use alloc::{boxed::Box, string::String, vec};
+use core::fmt::Write;
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::task::{Context, Poll, Waker};
+use log::LevelFilter;
 
 pub fn run_tests() {
     check_for_features();
+    check_frame_allocator();
+    check_serial_options();
+    check_log_filters();
+    check_log_ring_buffer();
+    check_log_ring();
+    check_semaphore_order();
+    check_mpsc_close();
+    check_broadcast_lag();
 }
 
 
@@ -12,3 +28,194 @@ fn check_for_features() {
     assert!(features.has_sse3());
     println!("CPU features test: SUCCESS");
 }
+
+fn check_frame_allocator() {
+    // 256 frames, the first 8 are used
+    let bitmap = Box::leak(vec![0u64; 4].into_boxed_slice());
+    bitmap[0] = 0xFF;
+    let mut allocator = BitmapFrameAllocator::with_bitmap(bitmap, 256);
+    assert_eq!(allocator.free_frames(), 248);
+
+    let first = allocator.alloc().unwrap();
+    let second = allocator.alloc().unwrap();
+    assert_eq!(first.start_address().as_u64(), 8 * FRAME_SIZE);
+    assert_eq!(second.start_address().as_u64(), 9 * FRAME_SIZE);
+    allocator.free(first);
+    assert_eq!(allocator.alloc(), Some(first));
+    assert_eq!(allocator.free_frames(), 246);
+
+    let align = 16 * FRAME_SIZE;
+    let block = allocator.alloc_contiguous(4, align).unwrap();
+    assert_eq!(block.start_address().as_u64(), 16 * FRAME_SIZE);
+    assert_eq!(allocator.free_frames(), 242);
+    assert_eq!(allocator.alloc_contiguous(0, FRAME_SIZE), None);
+    assert_eq!(allocator.alloc_contiguous(300, FRAME_SIZE), None);
+    allocator.free_contiguous(block, 4);
+    assert_eq!(allocator.free_frames(), 246);
+
+    let mut allocated = 0;
+    while allocator.alloc().is_some() {
+        allocated += 1;
+    }
+    assert_eq!(allocated, 246);
+    assert_eq!(allocator.free_frames(), 0);
+    println!("frame allocator test: SUCCESS");
+}
+
+fn check_serial_options() {
+    assert_eq!(com::parse_port("ttyS0"), Some((0, com::DEFAULT_BAUD_RATE)));
+    assert_eq!(com::parse_port("ttyS3,9600"), Some((3, 9600)));
+    assert_eq!(com::parse_port("ttyS4"), None);
+    assert_eq!(com::parse_port("ttyS1,0"), None);
+    assert_eq!(com::parse_port("ttyS1,1"), None);
+    assert_eq!(com::parse_port("ttyS1,abc"), None);
+    assert_eq!(com::parse_port("ttyS1,7"), None);
+    assert_eq!(com::parse_port("tty1"), None);
+    println!("serial options test: SUCCESS");
+}
+
+fn check_log_filters() {
+    assert!(klog::is_module_prefix("kernel::task", "kernel::task"));
+    assert!(klog::is_module_prefix("kernel::task", "kernel::task::executor"));
+    assert!(!klog::is_module_prefix("kernel::task", "kernel::tasks"));
+    assert!(!klog::is_module_prefix("kernel::task::executor", "kernel::task"));
+
+    let (filters, invalid) = klog::Filters::parse("warn,kernel::task=debug,kernel::task::executor=off,kernel::time=loud");
+    assert_eq!(invalid, ["kernel::time=loud"]);
+    assert_eq!(filters.level("kernel"), LevelFilter::Warn);
+    assert_eq!(filters.level("kernel::tasks"), LevelFilter::Warn);
+    assert_eq!(filters.level("kernel::task::join"), LevelFilter::Debug);
+    assert_eq!(filters.level("kernel::task::executor"), LevelFilter::Off);
+    assert_eq!(filters.level("kernel::time"), LevelFilter::Warn);
+    println!("log filters test: SUCCESS");
+}
+
+fn check_log_ring_buffer() {
+    let mut ring = klog::RingBuffer::<16>::new();
+    ring.write_str("first\nsecond\n").unwrap();
+    assert_eq!(ring.contents(), b"first\nsecond\n");
+
+    // wraps around and overwrites most of "first"
+    ring.write_str("third\n").unwrap();
+    assert_eq!(ring.contents(), b"second\nthird\n");
+
+    // exactly full, nothing is overwritten yet
+    let mut ring = klog::RingBuffer::<8>::new();
+    ring.write_str("abc\ndef\n").unwrap();
+    assert_eq!(ring.contents(), b"abc\ndef\n");
+
+    // overwrites all of "abc\n", "def\n" is still whole
+    ring.write_str("ghij").unwrap();
+    assert_eq!(ring.contents(), b"def\nghij");
+    println!("log ring buffer test: SUCCESS");
+}
+
+fn check_log_ring() {
+    let drain = |ring: &klog::LogRing| {
+        let mut records = String::new();
+        ring.drain(3, |record| records.push_str(record));
+        records
+    };
+
+    // the long record wraps around the end of the ring
+    let ring = klog::LogRing::new();
+    for _ in 0..klog::RING_SLOTS - 2 {
+        ring.push(&"short");
+    }
+    drain(&ring);
+    let long = "x".repeat(600);
+    ring.push(&long);
+    assert_eq!(drain(&ring), long + "\n");
+
+    let longest = "y".repeat(5000);
+    ring.push(&longest);
+    let records = drain(&ring);
+    assert!(records.ends_with(" [truncated]\n"));
+    assert!(records.trim_end_matches(" [truncated]\n").bytes().all(|byte| byte == b'y'));
+
+    let ring = klog::LogRing::new();
+    for index in 0..klog::RING_SLOTS + 2 {
+        ring.push(&index);
+    }
+    let records = drain(&ring);
+    let mut lines = records.lines();
+    assert_eq!(lines.next(), Some("[2 log records dropped on cpu3]"));
+    assert!(lines.map(|line| line.parse::<usize>().unwrap()).eq(0..klog::RING_SLOTS));
+    assert_eq!(drain(&ring), "");
+    println!("log ring test: SUCCESS");
+}
+
+/// Polls `future` once, nothing is woken.
+fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+fn check_semaphore_order() {
+    let semaphore = Semaphore::new(1);
+    let permit = semaphore.try_acquire().unwrap();
+    let mut many = pin!(semaphore.acquire_many(2));
+    let mut one = pin!(semaphore.acquire());
+    assert!(poll_once(many.as_mut()).is_pending());
+    assert!(poll_once(one.as_mut()).is_pending());
+
+    // the released permit isn't enough for the first waiter and the second one can't pass it
+    drop(permit);
+    assert!(poll_once(one.as_mut()).is_pending());
+    assert!(matches!(semaphore.try_acquire(), Err(TryAcquireError::NoPermits)));
+
+    semaphore.add_permits(1);
+    let permits = match poll_once(many.as_mut()) {
+        Poll::Ready(Ok(permits)) => permits,
+        _ => panic!("semaphore skipped the first waiter"),
+    };
+    assert!(poll_once(one.as_mut()).is_pending());
+    drop(permits);
+    assert!(matches!(poll_once(one.as_mut()), Poll::Ready(Ok(_))));
+    assert_eq!(semaphore.available_permits(), 2);
+    println!("semaphore order test: SUCCESS");
+}
+
+fn check_mpsc_close() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let second = sender.clone();
+    sender.try_send(1).unwrap();
+    assert_eq!(second.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    drop(sender);
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Some(1)));
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Pending);
+
+    // values sent before the last sender is dropped still arrive
+    second.try_send(3).unwrap();
+    drop(second);
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Some(3)));
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(None));
+    assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+
+    let (sender, receiver) = mpsc::channel(1);
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(4), Err(mpsc::TrySendError::Closed(4)));
+    println!("mpsc close test: SUCCESS");
+}
+
+fn check_broadcast_lag() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    for value in 0..5 {
+        assert_eq!(sender.send(value), Ok(1));
+    }
+    let mut late = sender.subscribe();
+
+    // only the last two values are kept
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Err(broadcast::RecvError::Lagged(3))));
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Ok(3)));
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Ok(4)));
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Pending);
+    assert_eq!(poll_once(pin!(late.recv())), Poll::Pending);
+
+    assert_eq!(sender.send(5), Ok(2));
+    drop(sender);
+    assert_eq!(poll_once(pin!(receiver.recv())), Poll::Ready(Ok(5)));
+    assert_eq!(poll_once(pin!(late.recv())), Poll::Ready(Ok(5)));
+    assert_eq!(poll_once(pin!(late.recv())), Poll::Ready(Err(broadcast::RecvError::Closed)));
+    println!("broadcast lag test: SUCCESS");
+}
@@ -0,0 +1,88 @@
+use core::arch::{asm, global_asm};
+
+
+global_asm!(r#"
+.section .text
+.global thread_switch
+thread_switch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#);
+
+extern "C" {
+    /// Saves the callee-saved registers on the current stack, stores its pointer in `prev_rsp`
+    /// and continues on `next_rsp`.
+    fn thread_switch(prev_rsp: *mut u64, next_rsp: u64);
+}
+
+// callee-saved registers `thread_switch` pops from a new stack
+const SAVED_REGISTERS: usize = 6;
+
+
+/// x87/SSE state as written by `fxsave64`.
+#[repr(C, align(16))]
+struct FxArea([u8; 512]);
+
+impl FxArea {
+    /// State after `fninit` with all SSE exceptions masked.
+    fn initial() -> Self {
+        let mut area = [0u8; 512];
+        area[0..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        area[24..28].copy_from_slice(&0x1F80u32.to_le_bytes());
+        FxArea(area)
+    }
+}
+
+
+/// Everything of a thread that isn't on its stack while it is switched out.
+pub(super) struct Context {
+    rsp: u64,
+    fx: FxArea,
+}
+
+impl Context {
+    /// Context of the code already running on the CPU, filled in by its first switch.
+    pub(super) fn current() -> Self {
+        Context { rsp: 0, fx: FxArea::initial() }
+    }
+
+    /// Context that starts executing `entry` on the stack ending at `stack_top`.
+    pub(super) fn new(stack_top: u64, entry: extern "C" fn() -> !) -> Self {
+        let mut rsp = stack_top & !0xF;
+        let mut push = |value: u64| unsafe {
+            rsp -= 8;
+            (rsp as *mut u64).write(value);
+        };
+
+        // fake return address of `entry`, keeps the stack aligned as if it was called
+        push(0);
+        push(entry as usize as u64);
+        for _ in 0..SAVED_REGISTERS {
+            push(0);
+        }
+
+        Context { rsp, fx: FxArea::initial() }
+    }
+}
+
+/// Saves the running thread into `prev` and continues with `next`.
+///
+/// Returns once another switch comes back to `prev`. Interrupts have to be disabled.
+pub(super) unsafe fn switch(prev: *mut Context, next: *const Context) {
+    asm!("fxsave64 [{}]", in(reg) &mut (*prev).fx, options(nostack, preserves_flags));
+    asm!("fxrstor64 [{}]", in(reg) &(*next).fx, options(nostack, preserves_flags));
+    thread_switch(&mut (*prev).rsp, (*next).rsp);
+}
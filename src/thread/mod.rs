@@ -0,0 +1,466 @@
+//! Preemptive kernel threads.
+//!
+//! Every CPU round-robins its own threads, a thread stays on the CPU that spawned it.
+//! The LAPIC timer ends the time slice of a thread once another one is ready, the switch
+//! happens when the interrupt returns. The async executor runs as one thread per CPU.
+
+use crate::cpu::percpu;
+use crate::interrupts::ipi;
+use crate::memory::stack::Stack;
+use crate::time;
+use alloc::{boxed::Box, string::String, sync::Arc, task::Wake};
+use context::Context;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context as TaskContext, Poll, Waker},
+    time::Duration,
+};
+use crossbeam_queue::SegQueue;
+use futures_util::task::AtomicWaker;
+use spin::Once;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+mod context;
+
+
+const STACK_SIZE: usize = 64 * 1024;
+const TIME_SLICE_NS: u64 = 10_000_000;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunState {
+    Running,
+    /// In the ready queue of its CPU, or about to be.
+    Ready,
+    Blocked,
+    Exited,
+}
+
+struct State {
+    run: RunState,
+    // an `unpark` that came while the thread wasn't parked
+    unpark_token: bool,
+}
+
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    // index of the cpu the thread runs on
+    cpu: usize,
+    idle: bool,
+    // only touched by its own cpu with interrupts disabled
+    context: UnsafeCell<Context>,
+    _stack: Option<Stack>,
+    entry: spin::Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    state: spin::Mutex<State>,
+}
+
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Makes a parked thread runnable, or lets its next `park` return at once.
+    pub fn unpark(self: &Arc<Self>) {
+        let target = percpu::get(self.cpu).expect("thread on a cpu without per-cpu data");
+        let queued = without_interrupts(|| {
+            let mut state = self.state.lock();
+            match state.run {
+                RunState::Blocked => {
+                    // queued under the lock, `schedule` relies on a ready thread being queued
+                    state.run = RunState::Ready;
+                    target.threads.ready.push(self.clone());
+                    true
+                }
+                _ => {
+                    state.unpark_token = true;
+                    false
+                }
+            }
+        });
+        if !queued {
+            return;
+        }
+
+        // the cpu has to arm its time slice or leave `hlt`
+        match self.cpu == crate::cpu::current_index() {
+            true => without_interrupts(rearm_timer),
+            false => {
+                let _ = ipi::reschedule(self.cpu);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Thread").field("id", &self.id).field("name", &self.name).finish()
+    }
+}
+
+/// Thread bookkeeping of one CPU, part of its `PerCpu` block.
+pub struct CpuThreads {
+    ready: SegQueue<Arc<Thread>>,
+    // the lock is only taken with interrupts disabled
+    current: spin::Mutex<Option<Arc<Thread>>>,
+    idle: Once<Arc<Thread>>,
+    // the thread switched away from, dropped once the switch is done since it may be exiting
+    previous: spin::Mutex<Option<Arc<Thread>>>,
+    need_resched: AtomicBool,
+    // when the current thread got the cpu, in nanoseconds since boot
+    slice_start: AtomicU64,
+}
+
+impl CpuThreads {
+    pub(crate) const fn new() -> Self {
+        CpuThreads {
+            ready: SegQueue::new(),
+            current: spin::Mutex::new(None),
+            idle: Once::new(),
+            previous: spin::Mutex::new(None),
+            need_resched: AtomicBool::new(false),
+            slice_start: AtomicU64::new(0),
+        }
+    }
+}
+
+
+/// Turns the calling code into the idle thread of this CPU, has to run once per CPU
+/// before threads are spawned on it.
+pub fn init() {
+    // the context switch saves the x87/SSE state with fxsave
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+    }
+
+    let percpu = percpu::current();
+    let idle = Arc::new(Thread {
+        id: ThreadId::new(),
+        name: alloc::format!("idle/{}", percpu.index),
+        cpu: percpu.index,
+        idle: true,
+        context: UnsafeCell::new(Context::current()),
+        _stack: None,
+        entry: spin::Mutex::new(None),
+        state: spin::Mutex::new(State { run: RunState::Running, unpark_token: false }),
+    });
+
+    without_interrupts(|| {
+        *percpu.threads.current.lock() = Some(idle.clone());
+        percpu.threads.idle.call_once(|| idle);
+        percpu.threads.slice_start.store(time::now(), Ordering::Relaxed);
+    });
+}
+
+/// Runs the idle thread, halts until a thread is ready.
+pub fn idle() -> ! {
+    let threads = &percpu::current().threads;
+    loop {
+        interrupts::disable();
+        match threads.ready.is_empty() {
+            true => interrupts::enable_and_hlt(),
+            false => {
+                schedule();
+                interrupts::enable();
+            }
+        }
+    }
+}
+
+
+/// Starts `f` as a new thread on the calling CPU.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: spin::Mutex::new(None),
+        done: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+
+    let their_packet = packet.clone();
+    let entry = move || {
+        let result = f();
+        without_interrupts(|| *their_packet.result.lock() = Some(result));
+        their_packet.done.store(true, Ordering::Release);
+        their_packet.waker.wake();
+    };
+
+    let id = ThreadId::new();
+    let stack = Stack::new(STACK_SIZE).expect("no memory for a thread stack");
+    let stack_top = stack.top().as_u64();
+    let cpu = crate::cpu::current_index();
+    let thread = Arc::new(Thread {
+        id,
+        name: alloc::format!("thread/{}", id.0),
+        cpu,
+        idle: false,
+        context: UnsafeCell::new(Context::new(stack_top, thread_start)),
+        _stack: Some(stack),
+        entry: spin::Mutex::new(Some(Box::new(entry))),
+        state: spin::Mutex::new(State { run: RunState::Ready, unpark_token: false }),
+    });
+
+    percpu::current().threads.ready.push(thread.clone());
+    without_interrupts(rearm_timer);
+    JoinHandle { thread, packet }
+}
+
+/// First code a new thread runs, entered by returning from the context switch.
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let entry = current().entry.lock().take().expect("thread started twice");
+    interrupts::enable();
+
+    entry();
+    exit();
+}
+
+fn exit() -> ! {
+    interrupts::disable();
+    current().state.lock().run = RunState::Exited;
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+
+/// The thread running on this CPU.
+pub fn current() -> Arc<Thread> {
+    without_interrupts(|| {
+        percpu::current().threads.current.lock().clone().expect("threads not initialized on this cpu")
+    })
+}
+
+/// Gives the CPU to the next ready thread, returns at once if there is none.
+pub fn yield_now() {
+    without_interrupts(schedule);
+}
+
+/// Blocks until `unpark` is called on the thread, returns at once if it already was.
+pub fn park() {
+    without_interrupts(|| {
+        let thread = current();
+        {
+            let mut state = thread.state.lock();
+            if state.unpark_token {
+                state.unpark_token = false;
+                return;
+            }
+            state.run = RunState::Blocked;
+        }
+        drop(thread);
+        schedule();
+    });
+}
+
+/// Blocks the thread, not the CPU, for at least `duration`.
+pub fn sleep(duration: Duration) {
+    block_on(crate::task::time::sleep(duration));
+}
+
+/// Runs `future` to completion on the calling thread, parking it while the future is pending.
+///
+/// Called on an executor thread it stops every task of that CPU until it returns.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(current())));
+    let mut context = TaskContext::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        park();
+    }
+}
+
+struct ThreadWaker(Arc<Thread>);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+
+/// Picks the next thread of this CPU and switches to it. Interrupts have to be disabled.
+///
+/// A running thread goes to the back of the ready queue, a blocked or exited one stays off it.
+fn schedule() {
+    let threads = &percpu::current().threads;
+    threads.need_resched.store(false, Ordering::Relaxed);
+
+    let prev = threads.current.lock().clone().expect("threads not initialized on this cpu");
+    let was_running = {
+        let mut state = prev.state.lock();
+        let was_running = state.run == RunState::Running;
+        if was_running {
+            state.run = RunState::Ready;
+        }
+        was_running
+    };
+
+    // a thread that blocked and was unparked again is already queued by `unpark`
+    let next = match threads.ready.pop() {
+        Some(next) => next,
+        None if was_running => prev.clone(),
+        None => threads.idle.get().unwrap().clone(),
+    };
+    if was_running && !prev.idle && !Arc::ptr_eq(&prev, &next) {
+        threads.ready.push(prev.clone());
+    }
+    next.state.lock().run = RunState::Running;
+
+    if Arc::ptr_eq(&prev, &next) {
+        rearm_timer();
+        return;
+    }
+
+    threads.slice_start.store(time::now(), Ordering::Relaxed);
+    *threads.current.lock() = Some(next.clone());
+    rearm_timer();
+
+    let prev_context = prev.context.get();
+    let next_context = next.context.get() as *const Context;
+    *threads.previous.lock() = Some(prev);
+    drop(next);
+
+    unsafe {
+        context::switch(prev_context, next_context);
+    }
+    finish_switch();
+}
+
+fn finish_switch() {
+    let previous = percpu::current().threads.previous.lock().take();
+    drop(previous);
+}
+
+
+/// Arms the TSC-deadline timer for the end of the time slice and, on the BSP, for the
+/// next timer future. Interrupts have to be disabled.
+pub(crate) fn rearm_timer() {
+    if !time::is_tickless() {
+        return;
+    }
+
+    let percpu = percpu::current();
+    let threads = &percpu.threads;
+    let wheel = match percpu.index {
+        0 => crate::task::time::next_deadline(),
+        _ => None,
+    };
+    let slice = match threads.ready.is_empty() {
+        true => None,
+        false => Some(threads.slice_start.load(Ordering::Relaxed) + TIME_SLICE_NS),
+    };
+    let deadline = match (wheel, slice) {
+        (Some(wheel), Some(slice)) => Some(wheel.min(slice)),
+        (wheel, slice) => wheel.or(slice),
+    };
+    time::arm_deadline(deadline);
+}
+
+/// Called by the LAPIC timer interrupt handler of every CPU.
+pub(crate) fn on_timer() {
+    let threads = &percpu::current().threads;
+    if threads.idle.get().is_none() {
+        return;
+    }
+
+    let elapsed = time::now().saturating_sub(threads.slice_start.load(Ordering::Relaxed));
+    if elapsed >= TIME_SLICE_NS && !threads.ready.is_empty() {
+        threads.need_resched.store(true, Ordering::Relaxed);
+    }
+    rearm_timer();
+}
+
+/// Called when an interrupt returns to thread context, switches if the time slice ended.
+pub(crate) fn preempt() {
+    if percpu::current().threads.need_resched.load(Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Whether another thread waits for this CPU.
+pub fn has_ready() -> bool {
+    !percpu::current().threads.ready.is_empty()
+}
+
+
+struct Packet<T> {
+    result: spin::Mutex<Option<T>>,
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Owned permission to wait for a thread, either blocking with `join` or by awaiting it.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.done.load(Ordering::Acquire)
+    }
+
+    /// Blocks the calling thread until the thread returned.
+    pub fn join(mut self) -> T {
+        block_on(poll_fn(|cx| Pin::new(&mut self).poll(cx)))
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<T> {
+        self.packet.waker.register(cx.waker());
+        if !self.packet.done.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        match without_interrupts(|| self.packet.result.lock().take()) {
+            Some(result) => Poll::Ready(result),
+            None => panic!("thread JoinHandle polled after completion"),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use crate::acpi::{acpi::HpetInfo, KernelHandler};
+use crate::interrupts::{
+    handler::{Handler, RegisterError, Registration},
+    routing::{Polarity, TriggerMode},
+    Irq,
+};
+use crate::memory;
+use acpi::AcpiTables;
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr};
+
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+const fn timer_config(n: u8) -> usize { 0x100 + 0x20 * n as usize }
+const fn timer_comparator(n: u8) -> usize { 0x108 + 0x20 * n as usize }
+
+// general config
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+
+// timer config
+const TN_INT_TYPE_CNF: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+const TN_32MODE_CNF: u64 = 1 << 8;
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+const TN_INT_ROUTE_MASK: u64 = 0b11111 << TN_INT_ROUTE_SHIFT;
+
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+
+static HPET: Once<Hpet> = Once::new();
+// which comparators are handed out
+static COMPARATORS_USED: Mutex<u32> = Mutex::new(0);
+
+
+pub struct Hpet {
+    base: VirtAddr,
+    // femtoseconds per counter tick
+    period: u64,
+    comparators: u8,
+    counter_64bit: bool,
+}
+
+unsafe impl Sync for Hpet {}
+unsafe impl Send for Hpet {}
+
+impl Hpet {
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { (self.base + offset as u64).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { (self.base + offset as u64).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        (FEMTOS_PER_SEC / self.period as u128) as u64
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period as u128 / FEMTOS_PER_NANO) as u64
+    }
+
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FEMTOS_PER_NANO).div_ceil(self.period as u128) as u64
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    /// A 32 bit counter wraps every few minutes, so it is only used for calibration and comparators.
+    pub fn counter_is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+}
+
+
+/// Finds the HPET in the ACPI tables, maps it and starts its main counter from 0.
+pub fn init(tables: &AcpiTables<KernelHandler>) -> Option<&'static Hpet> {
+    let info = match HpetInfo::new(tables) {
+        Ok(info) => info,
+        Err(_) => {
+            log::info!("no hpet");
+            return None;
+        }
+    };
+
+    let base = memory::map_mmio(PhysAddr::new(info.base_address as u64), 1024);
+    let mut hpet = Hpet {
+        base,
+        period: 0,
+        comparators: 0,
+        counter_64bit: false,
+    };
+    let capabilities = hpet.read(GENERAL_CAPABILITIES);
+    hpet.period = capabilities >> 32;
+    hpet.comparators = ((capabilities >> 8) & 0x1F) as u8 + 1;
+    hpet.counter_64bit = capabilities & (1 << 13) != 0;
+
+    // the spec caps the period at 100 ns
+    if hpet.period == 0 || hpet.period > 100_000_000 {
+        log::warn!("hpet reports an invalid period of {} fs", hpet.period);
+        return None;
+    }
+
+    // stop, reset and start the main counter, comparators are routed through the io apic
+    let config = hpet.read(GENERAL_CONFIG) & !(ENABLE_CNF | LEG_RT_CNF);
+    hpet.write(GENERAL_CONFIG, config);
+    hpet.write(MAIN_COUNTER, 0);
+    for n in 0..hpet.comparators {
+        let timer = hpet.read(timer_config(n)) & !(TN_INT_ENB_CNF | TN_TYPE_CNF);
+        hpet.write(timer_config(n), timer);
+    }
+    hpet.write(GENERAL_CONFIG, config | ENABLE_CNF);
+
+    log::info!(
+        "hpet {} Hz, {} comparators, {} bit counter",
+        hpet.frequency(),
+        hpet.comparators,
+        if hpet.counter_64bit { 64 } else { 32 },
+    );
+
+    Some(HPET.call_once(|| hpet))
+}
+
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+
+/// Busy waits `ms` milliseconds on the main counter and calls `on_start` right after the first read.
+pub fn wait_ms(hpet: &Hpet, ms: u64, on_start: impl FnOnce()) {
+    let ticks = hpet.ns_to_ticks(ms * 1_000_000);
+    let start = hpet.counter();
+    on_start();
+
+    // wrapping so a 32 bit counter rolling over doesn't matter
+    while (hpet.counter().wrapping_sub(start) & counter_mask(hpet)) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+fn counter_mask(hpet: &Hpet) -> u64 {
+    match hpet.counter_64bit {
+        true => u64::MAX,
+        false => u32::MAX as u64,
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComparatorMode {
+    OneShot,
+    Periodic,
+}
+
+#[derive(Debug)]
+pub enum ComparatorError {
+    NotPresent,
+    /// Every comparator is in use, or none supports periodic mode.
+    NoFreeComparator,
+    /// None of the io apic inputs the comparator can drive is free.
+    NoRoute,
+    Register(RegisterError),
+}
+
+/// A comparator handed out by `start_comparator`, pass it to `stop_comparator` to release it.
+#[derive(Debug)]
+#[must_use]
+pub struct Comparator {
+    index: u8,
+    registration: Registration,
+}
+
+impl Comparator {
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+}
+
+
+/// Fires `handler` once after `delay_ns` or every `delay_ns`, as an alternative to the LAPIC timer.
+pub fn start_comparator(mode: ComparatorMode, delay_ns: u64, handler: Handler) -> Result<Comparator, ComparatorError> {
+    let hpet = get().ok_or(ComparatorError::NotPresent)?;
+
+    let index = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut used = COMPARATORS_USED.lock();
+        let index = (0..hpet.comparators).find(|&n| {
+            let periodic_ok = mode == ComparatorMode::OneShot || hpet.read(timer_config(n)) & TN_PER_INT_CAP != 0;
+            *used & (1 << n) == 0 && periodic_ok
+        })?;
+        *used |= 1 << index;
+        Some(index)
+    }).ok_or(ComparatorError::NoFreeComparator)?;
+
+    let release = || x86_64::instructions::interrupts::without_interrupts(|| {
+        *COMPARATORS_USED.lock() &= !(1 << index);
+    });
+
+    // the upper half of the config lists the io apic inputs the comparator can drive
+    let config = hpet.read(timer_config(index));
+    let routes = (config >> 32) as u32;
+
+    // take the first input that isn't already used by another device
+    let mut result = Err(ComparatorError::NoRoute);
+    for gsi in (0..32).filter(|gsi| routes & (1 << gsi) != 0) {
+        match crate::interrupts::register(Irq::Gsi(gsi, Polarity::ActiveHigh, TriggerMode::Edge), handler) {
+            Ok(registration) => {
+                result = Ok((gsi, registration));
+                break;
+            }
+            Err(RegisterError::Busy) => continue,
+            Err(err) => {
+                result = Err(ComparatorError::Register(err));
+                break;
+            }
+        }
+    }
+    let (gsi, registration) = match result {
+        Ok(route) => route,
+        Err(err) => {
+            release();
+            return Err(err);
+        }
+    };
+
+    let ticks = hpet.ns_to_ticks(delay_ns).max(1);
+    let mut config = config & !(TN_INT_ROUTE_MASK | TN_INT_TYPE_CNF | TN_TYPE_CNF | TN_32MODE_CNF);
+    config |= (gsi as u64) << TN_INT_ROUTE_SHIFT;
+
+    match mode {
+        ComparatorMode::OneShot => {
+            hpet.write(timer_config(index), config);
+            hpet.write(timer_comparator(index), hpet.counter().wrapping_add(ticks));
+        }
+        ComparatorMode::Periodic => {
+            // with VAL_SET the first write sets the next match and the second one the period
+            hpet.write(timer_config(index), config | TN_TYPE_CNF | TN_VAL_SET_CNF);
+            hpet.write(timer_comparator(index), hpet.counter().wrapping_add(ticks));
+            hpet.write(timer_comparator(index), ticks);
+        }
+    }
+    hpet.write(timer_config(index), hpet.read(timer_config(index)) | TN_INT_ENB_CNF);
+
+    Ok(Comparator {
+        index,
+        registration,
+    })
+}
+
+/// Disables the comparator and removes its handler.
+pub fn stop_comparator(comparator: Comparator) {
+    let hpet = get().expect("hpet not initialized");
+    let config = hpet.read(timer_config(comparator.index)) & !(TN_INT_ENB_CNF | TN_TYPE_CNF);
+    hpet.write(timer_config(comparator.index), config);
+
+    crate::interrupts::unregister(comparator.registration);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *COMPARATORS_USED.lock() &= !(1 << comparator.index);
+    });
+}
@@ -0,0 +1,212 @@
+use crate::interrupts::apic::{TimerMode, LOCAL_APIC};
+use crate::CPUID;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod hpet;
+pub mod pit;
+
+
+pub const DEFAULT_TICK_FREQUENCY: u32 = 1000;
+const CALIBRATION_MS: u64 = 10;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+// nanoseconds since boot at the last change of the tick frequency
+static TICK_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+// after the divider
+static LAPIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// 0 unless the TSC is invariant
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+static HPET_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+// the LAPIC timer is in TSC-deadline mode and only fires when something is waiting
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+// `now` never goes backwards, even while a tick is pending with interrupts disabled
+static LAST_NOW: AtomicU64 = AtomicU64::new(0);
+
+
+/// Calibrates the LAPIC timer (and the TSC when it is invariant) against the HPET, or the PIT
+/// without one, and starts the periodic tick at `DEFAULT_TICK_FREQUENCY`.
+///
+/// With an invariant TSC and TSC-deadline support the timer is left disarmed instead,
+/// see `arm_deadline`. `hpet::init` has to run first for the HPET to be used.
+pub fn init() {
+    let invariant_tsc = CPUID
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc());
+    let tsc_deadline = CPUID
+        .get_feature_info()
+        .map_or(false, |info| info.has_tsc_deadline());
+
+    without_interrupts(|| unsafe {
+        LOCAL_APIC.enable();
+
+        let mut tsc_start = 0;
+        let start = || {
+            LOCAL_APIC.start_timer(TimerMode::OneShot, u32::MAX, true);
+            tsc_start = _rdtsc();
+            if let Some(hpet) = hpet::get() {
+                HPET_AT_BOOT.store(hpet.counter(), Ordering::Relaxed);
+            }
+        };
+        match hpet::get() {
+            Some(hpet) => hpet::wait_ms(hpet, CALIBRATION_MS, start),
+            None => pit::wait_ms(CALIBRATION_MS, start),
+        }
+        let tsc_end = _rdtsc();
+        let lapic_elapsed = u32::MAX - LOCAL_APIC.timer_current_count();
+        LOCAL_APIC.stop_timer();
+
+        LAPIC_TIMER_FREQUENCY.store(lapic_elapsed as u64 * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+        if invariant_tsc {
+            TSC_FREQUENCY.store((tsc_end - tsc_start) * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+            TSC_AT_BOOT.store(tsc_start, Ordering::Relaxed);
+        }
+    });
+
+    log::info!(
+        "lapic timer {} Hz, invariant tsc: {} Hz, calibrated with the {}",
+        LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed),
+        TSC_FREQUENCY.load(Ordering::Relaxed),
+        if hpet::get().is_some() { "hpet" } else { "pit" },
+    );
+
+    if invariant_tsc && tsc_deadline {
+        log::info!("lapic timer in tsc-deadline mode, tickless");
+        TICKLESS.store(true, Ordering::Relaxed);
+        unsafe {
+            LOCAL_APIC.start_timer(TimerMode::TscDeadline, 0, false);
+        }
+    }
+
+    set_tick_frequency(DEFAULT_TICK_FREQUENCY);
+}
+
+/// Reprograms the periodic LAPIC timer to fire `hz` times a second.
+///
+/// When tickless this only sets the resolution of the timer wheel.
+pub fn set_tick_frequency(hz: u32) {
+    let initial_count = LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed) / hz as u64;
+    assert!(initial_count > 0 && initial_count <= u32::MAX as u64, "unsupported tick frequency {} Hz", hz);
+
+    without_interrupts(|| {
+        TICK_BASE_NS.store(now(), Ordering::Relaxed);
+        TICKS.store(0, Ordering::Relaxed);
+        TICK_FREQUENCY.store(hz, Ordering::Relaxed);
+        if !is_tickless() {
+            unsafe {
+                LOCAL_APIC.start_timer(TimerMode::Periodic, initial_count as u32, false);
+            }
+        }
+    });
+}
+
+/// Starts the LAPIC timer of an application processor in the mode `init` chose for the BSP.
+pub fn init_ap() {
+    unsafe {
+        match is_tickless() {
+            true => LOCAL_APIC.start_timer(TimerMode::TscDeadline, 0, false),
+            false => {
+                let initial_count = LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed) / tick_frequency() as u64;
+                LOCAL_APIC.start_timer(TimerMode::Periodic, initial_count as u32, false);
+            }
+        }
+    }
+}
+
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Arms the TSC-deadline timer to fire at `deadline` (nanoseconds since boot), `None` disarms it.
+///
+/// Does nothing unless the timer is tickless.
+pub fn arm_deadline(deadline: Option<u64>) {
+    if !is_tickless() {
+        return;
+    }
+
+    let tsc_deadline = match deadline {
+        Some(deadline) => {
+            let tsc_frequency = TSC_FREQUENCY.load(Ordering::Relaxed) as u128;
+            let ticks = (deadline as u128 * tsc_frequency).div_ceil(NANOS_PER_SEC) as u64;
+            // 0 would disarm a deadline at boot
+            (TSC_AT_BOOT.load(Ordering::Relaxed) + ticks).max(1)
+        }
+        None => 0,
+    };
+    unsafe {
+        LOCAL_APIC.set_tsc_deadline(tsc_deadline);
+    }
+}
+
+pub fn tick_frequency() -> u32 {
+    TICK_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Called by the LAPIC timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+
+/// Nanoseconds since the clock was calibrated.
+///
+/// Prefers the invariant TSC, then a 64 bit HPET and falls back to counting LAPIC ticks.
+pub fn now() -> u64 {
+    let now = match (TSC_FREQUENCY.load(Ordering::Relaxed), hpet::get()) {
+        (0, Some(hpet)) if hpet.counter_is_64bit() => {
+            hpet.ticks_to_ns(hpet.counter() - HPET_AT_BOOT.load(Ordering::Relaxed))
+        }
+        (0, _) => tick_clock_ns(),
+        (tsc_frequency, _) => {
+            let elapsed = unsafe { _rdtsc() } - TSC_AT_BOOT.load(Ordering::Relaxed);
+            (elapsed as u128 * NANOS_PER_SEC / tsc_frequency as u128) as u64
+        }
+    };
+
+    let last = LAST_NOW.fetch_max(now, Ordering::Relaxed);
+    now.max(last)
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now())
+}
+
+/// Counts whole ticks and interpolates inside the current one with the LAPIC timer count.
+fn tick_clock_ns() -> u64 {
+    let hz = TICK_FREQUENCY.load(Ordering::Relaxed);
+    let lapic_frequency = LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed);
+    if hz == 0 || lapic_frequency == 0 {
+        return 0;
+    }
+
+    loop {
+        let ticks = TICKS.load(Ordering::Acquire);
+        let (initial, current) = unsafe {
+            (LOCAL_APIC.timer_initial_count(), LOCAL_APIC.timer_current_count())
+        };
+        if TICKS.load(Ordering::Acquire) != ticks {
+            continue;
+        }
+
+        let tick_ns = ticks as u128 * NANOS_PER_SEC / hz as u128;
+        let into_tick_ns = (initial - current) as u128 * NANOS_PER_SEC / lapic_frequency as u128;
+        return TICK_BASE_NS.load(Ordering::Relaxed) + (tick_ns + into_tick_ns) as u64;
+    }
+}
+
+/// Busy waits, for the short delays hardware initialization needs.
+pub fn spin_wait(duration: Duration) {
+    let end = now() + duration.as_nanos() as u64;
+    while now() < end {
+        core::hint::spin_loop();
+    }
+}
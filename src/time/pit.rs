@@ -0,0 +1,43 @@
+use x86_64::instructions::port::Port;
+
+
+/// Input clock of the 8253/8254 PIT.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+const GATE_PORT: u16 = 0x61;
+
+
+/// Busy waits `ms` milliseconds on PIT channel 2 and calls `on_start` right after
+/// the countdown started, at most 54 ms fit in the 16 bit counter.
+pub fn wait_ms(ms: u64, on_start: impl FnOnce()) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count <= u16::MAX as u64, "PIT wait of {} ms is too long", ms);
+
+    let mut gate: Port<u8> = Port::new(GATE_PORT);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA);
+
+    unsafe {
+        // gate low and speaker off while programming
+        let value = gate.read() & !0b11;
+        gate.write(value);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // the rising edge of the gate starts the countdown
+        gate.write(value | 0b01);
+        on_start();
+
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        gate.write(value);
+    }
+}